@@ -0,0 +1,42 @@
+use crate::http::HttpResponse;
+use actix_web::body::BoxBody;
+use actix_web::{HttpRequest, Responder};
+use std::sync::OnceLock;
+
+/// Receives every `Audited` response right before it is sent, after the response hook
+/// and the default headers were applied.
+/// The response payload is given as built, so JSON bodies are not serialized twice.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, req: &HttpRequest, response: &HttpResponse);
+}
+
+static AUDIT_SINK: OnceLock<Box<dyn AuditSink>> = OnceLock::new();
+
+/// Register the sink used by `Audited` responses.
+/// It can only be set once, usually at startup; later calls are ignored.
+pub fn set_audit_sink(sink: impl AuditSink + 'static) {
+    let _ = AUDIT_SINK.set(Box::new(sink));
+}
+
+/// A responder passing the response to the registered `AuditSink` before sending it.
+/// Handlers return `Result<Audited, HttpError>` and wrap their response with `Audited::from`.
+pub struct Audited(pub HttpResponse);
+
+impl From<HttpResponse> for Audited {
+    fn from(response: HttpResponse) -> Self {
+        Audited(response)
+    }
+}
+
+impl Responder for Audited {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> actix_web::HttpResponse<Self::Body> {
+        let mut response = self.0;
+        response.finalize();
+        if let Some(sink) = AUDIT_SINK.get() {
+            sink.record(req, &response);
+        }
+        response.into_actix()
+    }
+}
@@ -0,0 +1,221 @@
+use crate::http::{bad_request, HttpError, ValidationErrorResponse};
+use actix_web::dev::Payload;
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::{web, FromRequest, HttpRequest};
+use apistos::paths::{Parameter, RequestBody};
+use apistos::reference_or::ReferenceOr;
+use apistos::ApiComponent;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use schemars::schema::Schema;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use validator::Validate;
+
+/// A JSON body extractor that runs `validator::Validate` on the deserialized value.
+/// Validation failures are returned as `HttpError::ValidationError`, while bodies that can not
+/// be deserialized into `T` are returned as `HttpError::Malformed` with the same shape,
+/// naming the offending field in the `field` param.
+pub struct ValidatedJson<T>(pub T);
+
+impl<T> ValidatedJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for ValidatedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for ValidatedJson<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<T> FromRequest for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<serde_json::Value>::from_request(req, payload);
+        Box::pin(async move {
+            let body = match json.await {
+                Ok(body) => body.into_inner(),
+                Err(e) => match e.as_error::<JsonPayloadError>() {
+                    Some(JsonPayloadError::Deserialize(e)) => {
+                        return Err(HttpError::Malformed(
+                            ValidationErrorResponse::deserialize_error(None, e.to_string()),
+                        )
+                        .into())
+                    }
+                    _ => return Err(e),
+                },
+            };
+            let value: T = serde_path_to_error::deserialize(body).map_err(|e| {
+                let field = e.path().to_string();
+                let field = if field == "." {
+                    None
+                } else {
+                    Some(field.as_str())
+                };
+                HttpError::Malformed(ValidationErrorResponse::deserialize_error(
+                    field,
+                    e.inner().to_string(),
+                ))
+            })?;
+            value.validate().map_err(HttpError::from)?;
+            Ok(ValidatedJson(value))
+        })
+    }
+}
+
+impl<T> ApiComponent for ValidatedJson<T>
+where
+    T: ApiComponent,
+{
+    fn required() -> bool {
+        T::required()
+    }
+
+    fn child_schemas() -> Vec<(String, ReferenceOr<Schema>)> {
+        T::child_schemas()
+    }
+
+    fn raw_schema() -> Option<ReferenceOr<Schema>> {
+        T::raw_schema()
+    }
+
+    fn schema() -> Option<(String, ReferenceOr<Schema>)> {
+        T::schema()
+    }
+}
+
+/// A query string extractor that runs `validator::Validate` on the deserialized value,
+/// the query counterpart of `ValidatedJson`. Deserialization behaves like `web::Query`,
+/// including `serde` defaults for missing params. Query strings that can not be deserialized
+/// are returned as `HttpError::Malformed`, and validation failures as `HttpError::ValidationError`.
+pub struct ValidatedQuery<T>(pub T);
+
+impl<T> ValidatedQuery<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for ValidatedQuery<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for ValidatedQuery<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<T> FromRequest for ValidatedQuery<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let query = match web::Query::<T>::from_query(req.query_string()) {
+            Ok(query) => query.into_inner(),
+            Err(QueryPayloadError::Deserialize(e)) => {
+                return ready(Err(HttpError::Malformed(
+                    ValidationErrorResponse::deserialize_error(None, e.to_string()),
+                )
+                .into()))
+            }
+            Err(e) => return ready(Err(e.into())),
+        };
+        ready(match query.validate() {
+            Ok(()) => Ok(ValidatedQuery(query)),
+            Err(e) => Err(HttpError::from(e).into()),
+        })
+    }
+}
+
+impl<T> ApiComponent for ValidatedQuery<T>
+where
+    web::Query<T>: ApiComponent,
+{
+    fn required() -> bool {
+        <web::Query<T>>::required()
+    }
+
+    fn child_schemas() -> Vec<(String, ReferenceOr<Schema>)> {
+        <web::Query<T>>::child_schemas()
+    }
+
+    fn raw_schema() -> Option<ReferenceOr<Schema>> {
+        <web::Query<T>>::raw_schema()
+    }
+
+    fn schema() -> Option<(String, ReferenceOr<Schema>)> {
+        <web::Query<T>>::schema()
+    }
+
+    fn request_body() -> Option<RequestBody> {
+        <web::Query<T>>::request_body()
+    }
+
+    fn parameters() -> Vec<Parameter> {
+        <web::Query<T>>::parameters()
+    }
+}
+
+/// The value of a required param, or a 400 naming the missing param.
+pub fn require_param<'a>(
+    params: &'a HashMap<String, String>,
+    key: &str,
+) -> Result<&'a str, HttpError> {
+    match params.get(key) {
+        Some(value) => Ok(value.as_str()),
+        None => bad_request(format!("Missing required parameter: {}", key)),
+    }
+}
+
+/// The value of a required param parsed as `T`, or a 400 if it is missing or can not be parsed.
+pub fn require_param_parsed<T: FromStr>(
+    params: &HashMap<String, String>,
+    key: &str,
+) -> Result<T, HttpError> {
+    let value = require_param(params, key)?;
+    match value.parse::<T>() {
+        Ok(value) => Ok(value),
+        Err(_) => bad_request(format!("Invalid value for parameter: {}", key)),
+    }
+}
+
+/// The value parsed as `T`, or a 400 naming the invalid id, e.g. for path params.
+pub fn parse_id<T: FromStr>(value: &str) -> Result<T, HttpError> {
+    match value.parse::<T>() {
+        Ok(id) => Ok(id),
+        Err(_) => bad_request(format!("Invalid id: {}", value)),
+    }
+}
+
+/// The value parsed as a `Uuid`, or a 400 naming the invalid UUID.
+#[cfg(feature = "uuid")]
+pub fn parse_uuid(value: &str) -> Result<uuid::Uuid, HttpError> {
+    match uuid::Uuid::parse_str(value) {
+        Ok(id) => Ok(id),
+        Err(_) => bad_request(format!("Invalid UUID: {}", value)),
+    }
+}
@@ -1,21 +1,64 @@
 use actix_web::body::BoxBody;
-use actix_web::http::header::ContentType;
-use actix_web::http::StatusCode;
+use actix_web::http::header::{
+    Charset, ContentDisposition, ContentType, DispositionParam, DispositionType, ExtendedValue,
+    Header, HeaderName, HeaderValue, HttpDate, Range, ACCEPT_RANGES, AGE, ALLOW,
+    CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LANGUAGE, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION,
+};
+#[cfg(feature = "jsonwebtoken")]
+use actix_web::http::header::WWW_AUTHENTICATE;
+use actix_web::http::{Method, StatusCode};
 use actix_web::{error, HttpRequest, Responder};
+use bytes::Bytes;
+#[cfg(feature = "postgres")]
+use futures::channel::{mpsc, oneshot};
+#[cfg(feature = "postgres")]
+use futures::SinkExt;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt, TryStreamExt};
+use scoped_futures::ScopedBoxFuture;
+use apistos::paths::MediaType;
 use apistos::{ApiComponent, ApiErrorComponent};
 use derive_more::Display;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::ser::SerializeMap;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Database, Transaction};
 use std::error::Error;
 use std::fmt;
 use std::fmt::Formatter;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
 use apistos::reference_or::ReferenceOr;
+use schemars::gen::SchemaSettings;
 use schemars::schema::Schema;
-use validator::{ValidationError, ValidationErrors};
+use schemars::JsonSchema;
+use std::collections::{BTreeMap, HashSet};
+use validator::{ValidationError, ValidationErrors, ValidationErrorsKind};
 
 pub type Response = Result<HttpResponse, HttpError>;
 
+pub type BodyStream = BoxStream<'static, Result<Bytes, HttpError>>;
+
+/// The body of a `HttpResponse`.
+/// `Json`, `Bytes` and `Empty` payloads are buffered and always sent with a `Content-Length`.
+/// `Stream` payloads are sent with `Content-Length` only when `length` is known, chunked otherwise;
+/// use `HttpResponse::buffered` to force it.
+/// Trailers can not be sent as actix-web has no API for trailing headers;
+/// streams needing a checksum or a total count should emit it as their last frame instead.
 pub enum HttpResponsePayload {
     Json(serde_json::Value),
+    Bytes {
+        body: Bytes,
+        content_type: String,
+    },
+    Stream {
+        body: BodyStream,
+        length: Option<u64>,
+    },
     Empty,
 }
 
@@ -23,6 +66,7 @@ pub enum HttpResponsePayload {
 /// Original http response from actix_web can not be shared between threads
 /// and cant be used inside async blocks
 /// This struct is a wrapper around actix_web::HttpResponse that can be shared between threads.
+/// Headers with a repeated name in `headers` are all sent.
 pub struct HttpResponse {
     pub status: StatusCode,
     pub payload: HttpResponsePayload,
@@ -40,6 +84,53 @@ impl ApiComponent for HttpResponse {
     }
 }
 
+/// Security headers appended by `HttpResponseBuilder::security_headers`.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    pub content_type_options: String,
+    pub frame_options: String,
+    pub content_security_policy: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            content_type_options: "nosniff".to_string(),
+            frame_options: "DENY".to_string(),
+            content_security_policy: "default-src 'none'; frame-ancestors 'none'".to_string(),
+        }
+    }
+}
+
+static DEFAULT_HEADERS: RwLock<Vec<(String, String)>> = RwLock::new(Vec::new());
+
+/// Headers added to every response, including error responses, e.g. `X-API-Version`.
+/// A header set on the response with the same name takes precedence.
+/// A later call replaces the headers set by the previous one.
+pub fn set_default_headers(headers: Vec<(String, String)>) {
+    *DEFAULT_HEADERS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = headers;
+}
+
+/// Add the default headers the response does not have yet.
+fn apply_default_headers<B>(response: &mut actix_web::HttpResponse<B>) {
+    let defaults = DEFAULT_HEADERS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for (key, value) in defaults.iter() {
+        let (Ok(key), Ok(value)) = (
+            HeaderName::try_from(key.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) else {
+            continue;
+        };
+        if !response.headers().contains_key(&key) {
+            response.headers_mut().insert(key, value);
+        }
+    }
+}
+
 pub struct HttpResponseBuilder {
     status: StatusCode,
     headers: Vec<(String, String)>,
@@ -53,14 +144,233 @@ impl HttpResponseBuilder {
         }
     }
 
+    /// Set a header, replacing any value already set for the name.
     pub fn add_header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let key = key.into();
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+        self.headers.push((key, value.into()));
+        self
+    }
+
+    /// Add a header, keeping any value already set for the name, for repeatable headers
+    /// such as `Set-Cookie`.
+    pub fn append_header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
         self.headers.push((key.into(), value.into()));
         self
     }
 
+    /// Remove every header with the given name, case-insensitively, e.g. an internal header
+    /// added by another layer that must not leave the service.
+    pub fn remove_header(mut self, key: &str) -> Self {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        self
+    }
+
+    /// Same as `remove_header`, for several header names.
+    pub fn strip_headers(mut self, keys: &[&str]) -> Self {
+        self.headers
+            .retain(|(k, _)| !keys.iter().any(|key| k.eq_ignore_ascii_case(key)));
+        self
+    }
+
+    /// Add the given header names to the `Vary` header, merging with any value already set.
+    pub fn vary(self, headers: &[&str]) -> Self {
+        self.merge_list_header("Vary", headers)
+    }
+
+    /// Add the `no-transform` directive to the `Cache-Control` header, merging with any directive
+    /// already set, so CDNs do not recompress or otherwise alter the body.
+    pub fn no_transform(self) -> Self {
+        self.merge_list_header("Cache-Control", &["no-transform"])
+    }
+
+    /// Add `max-age` and `stale-while-revalidate` directives, in seconds, to the `Cache-Control` header,
+    /// e.g. `cache_swr(60, 300)` for `max-age=60, stale-while-revalidate=300`.
+    /// Other directives already set, such as `no-transform`, are kept; existing values of these two are replaced.
+    pub fn cache_swr(mut self, max_age: u64, stale_while_revalidate: u64) -> Self {
+        if let Some((_, value)) = self
+            .headers
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case("cache-control"))
+        {
+            *value = value
+                .split(',')
+                .map(|directive| directive.trim())
+                .filter(|directive| {
+                    let name = directive.split('=').next().unwrap_or_default().trim();
+                    !name.eq_ignore_ascii_case("max-age")
+                        && !name.eq_ignore_ascii_case("stale-while-revalidate")
+                })
+                .collect::<Vec<&str>>()
+                .join(", ");
+        }
+        self.merge_list_header(
+            "Cache-Control",
+            &[
+                &format!("max-age={}", max_age),
+                &format!("stale-while-revalidate={}", stale_while_revalidate),
+            ],
+        )
+    }
+
+    /// Add the values to a comma separated list header, keeping the values already set.
+    fn merge_list_header(mut self, name: &str, values: &[&str]) -> Self {
+        let existing = self
+            .headers
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(name));
+
+        let mut merged: Vec<String> = match existing {
+            Some(index) => {
+                let (_, value) = self.headers.remove(index);
+                value
+                    .split(',')
+                    .map(|v| v.trim().to_string())
+                    .filter(|v| !v.is_empty())
+                    .collect()
+            }
+            None => vec![],
+        };
+
+        for value in values {
+            if !merged.iter().any(|v| v.eq_ignore_ascii_case(value)) {
+                merged.push(value.to_string());
+            }
+        }
+
+        self.headers.push((name.to_string(), merged.join(", ")));
+        self
+    }
+
+    /// Mark the response as deprecated with `Deprecation: true` and, if given, a `Sunset` HTTP-date.
+    pub fn deprecated(self, sunset: Option<SystemTime>) -> Self {
+        let builder = self.add_header("Deprecation", "true");
+        match sunset {
+            Some(sunset) => builder.add_header("Sunset", HttpDate::from(sunset).to_string()),
+            None => builder,
+        }
+    }
+
+    /// Add a `Warning` header, e.g. `warning(110, "Response is Stale")` for `110 - "Response is Stale"`.
+    /// Several warnings are merged into a single header.
+    pub fn warning(mut self, code: u16, text: &str) -> Self {
+        let text = text.replace('\\', "\\\\").replace('"', "\\\"");
+        let warning = format!("{:03} - \"{}\"", code, text);
+        match self
+            .headers
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case("warning"))
+        {
+            Some((_, value)) => {
+                value.push_str(", ");
+                value.push_str(&warning);
+                self
+            }
+            None => self.add_header("Warning", warning),
+        }
+    }
+
+    /// Add a `Link` relation, e.g. `link("/users?page=2", "next")` for `</users?page=2>; rel="next"`.
+    /// Several links are merged into a single header.
+    pub fn link(mut self, uri: &str, rel: &str) -> Self {
+        let link = format!("<{}>; rel=\"{}\"", uri, rel);
+        match self
+            .headers
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case("link"))
+        {
+            Some((_, value)) => {
+                value.push_str(", ");
+                value.push_str(&link);
+                self
+            }
+            None => self.add_header("Link", link),
+        }
+    }
+
+    /// Add a `Server-Timing` metric, e.g. `server_timing("db", elapsed)` for `db;dur=12.3`,
+    /// with the duration in milliseconds. Several metrics are merged into a single header.
+    pub fn server_timing(mut self, name: &str, duration: Duration) -> Self {
+        push_server_timing(&mut self.headers, name, duration);
+        self
+    }
+
+    /// Declare the language the response is rendered in, e.g. the one picked by `HttpError::localize`.
+    pub fn content_language(self, lang: &str) -> Self {
+        self.add_header(CONTENT_LANGUAGE.to_string(), lang)
+    }
+
+    /// Declare how many seconds ago the representation was generated, when serving it from a cache.
+    pub fn age(self, seconds: u64) -> Self {
+        self.add_header(AGE.to_string(), seconds.to_string())
+    }
+
+    /// Echo the request `X-Correlation-Id` header, or a new UUID when missing, in the response
+    /// and record it as the `correlation_id` field of the current tracing span, so client-reported
+    /// ids can be matched with the logs. The span must declare the field, e.g. with
+    /// `tracing::info_span!("request", correlation_id = tracing::field::Empty)`.
+    #[cfg(feature = "uuid")]
+    pub fn correlation_id(self, req: &HttpRequest) -> Self {
+        let correlation_id = req
+            .headers()
+            .get("X-Correlation-Id")
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.trim().is_empty())
+            .map(|value| value.to_string())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        tracing::Span::current().record("correlation_id", correlation_id.as_str());
+        self.add_header("X-Correlation-Id", correlation_id)
+    }
+
+    /// Append the default `SecurityHeaders`; a later `add_header` overrides any of them.
+    pub fn security_headers(self) -> Self {
+        self.security_headers_with(SecurityHeaders::default())
+    }
+
+    /// Append the given `SecurityHeaders`; a later `add_header` overrides any of them.
+    pub fn security_headers_with(self, headers: SecurityHeaders) -> Self {
+        self.add_header("X-Content-Type-Options", headers.content_type_options)
+            .add_header("X-Frame-Options", headers.frame_options)
+            .add_header("Content-Security-Policy", headers.content_security_policy)
+    }
+
+    /// Respond with the bytes as is, without copying them.
+    pub fn body_bytes(self, body: Bytes, content_type: impl Into<String>) -> HttpResponse {
+        HttpResponse {
+            status: self.status,
+            payload: HttpResponsePayload::Bytes {
+                body,
+                content_type: content_type.into(),
+            },
+            headers: self.headers,
+        }
+    }
+
+    /// Respond with a `text/plain` body, transcoded from UTF-8 to the encoding of the charset label.
+    /// Labels are resolved as browsers do, so `Content-Type` declares the encoding actually used,
+    /// which may differ from the label: e.g. `iso-8859-1` is encoded as `windows-1252`,
+    /// and UTF-16 labels as `utf-8`. An unknown charset falls back to UTF-8.
+    /// The encoding is lossy: characters the encoding can not represent are replaced by HTML numeric
+    /// character references such as `&#8364;`.
+    #[cfg(feature = "encoding_rs")]
+    pub fn text_with_charset(self, body: impl Into<String>, charset: &str) -> HttpResponse {
+        let body = body.into();
+        let encoding =
+            encoding_rs::Encoding::for_label(charset.as_bytes()).unwrap_or(encoding_rs::UTF_8);
+        let (encoded, encoding, _) = encoding.encode(&body);
+        let encoded = Bytes::from(encoded.into_owned());
+        self.body_bytes(
+            encoded,
+            format!(
+                "text/plain; charset={}",
+                encoding.name().to_ascii_lowercase()
+            ),
+        )
+    }
+
     pub fn finish(self) -> HttpResponse {
         HttpResponse {
-            status: self.status.clone(),
+            status: self.status,
             payload: HttpResponsePayload::Empty,
             headers: self.headers.clone(),
         }
@@ -72,7 +382,7 @@ impl HttpResponseBuilder {
     {
         match serde_json::to_value(&value) {
             Ok(body) => HttpResponse {
-                status: self.status.clone(),
+                status: self.status,
                 payload: HttpResponsePayload::Json(body),
                 headers: self.headers.clone(),
             },
@@ -81,42 +391,451 @@ impl HttpResponseBuilder {
             }
         }
     }
+
+    /// Same as `json`, but runs the validation on the value first, as a guardrail for response contracts,
+    /// e.g. bounding the number of items. A failed validation is logged and returned as a generic 500.
+    pub fn json_validated<T, V>(&self, value: T, validate: V) -> Response
+    where
+        T: Serialize + 'static,
+        V: FnOnce(&T) -> Result<(), String>,
+    {
+        if let Err(reason) = validate(&value) {
+            tracing::error!("Response body failed validation: {}", reason);
+            return internal_server_error("Internal server error");
+        }
+        Ok(self.json(value))
+    }
+
+    /// Same as `json`, but in debug builds with the `jsonschema` feature the serialized body is validated
+    /// against the `JsonSchema` of `T`, logging a warning for each mismatch, to catch drift between
+    /// the runtime body and the documented schema. Otherwise it is a plain `json`.
+    pub fn json_checked<T>(&self, value: T) -> HttpResponse
+    where
+        T: Serialize + JsonSchema + 'static,
+    {
+        let response = self.json(value);
+        #[cfg(all(debug_assertions, feature = "jsonschema"))]
+        if let HttpResponsePayload::Json(body) = &response.payload {
+            check_schema::<T>(body);
+        }
+        response
+    }
+
+    /// Same as `json`, but recursively removes `null` fields and the objects left empty by that.
+    /// This walks the whole serialized value once more, so prefer `json` for large bodies
+    /// or use `#[serde(skip_serializing_if = "Option::is_none")]` on the DTO instead.
+    pub fn json_skip_nulls<T>(&self, value: T) -> HttpResponse
+    where
+        T: Serialize + 'static,
+    {
+        match serde_json::to_value(&value) {
+            Ok(mut body) => {
+                strip_nulls(&mut body);
+                HttpResponse {
+                    status: self.status,
+                    payload: HttpResponsePayload::Json(body),
+                    headers: self.headers.clone(),
+                }
+            }
+            Err(_) => {
+                panic!("Failed to serialize response body");
+            }
+        }
+    }
+
+    /// Serialize the value as pretty-printed JSON, e.g. for debugging endpoints.
+    /// The value is serialized directly, so struct fields keep their declaration order,
+    /// while `json` goes through `serde_json::Value` and emits object keys sorted.
+    pub fn json_pretty<T>(&self, value: T) -> HttpResponse
+    where
+        T: Serialize + 'static,
+    {
+        match serde_json::to_vec_pretty(&value) {
+            Ok(body) => HttpResponse {
+                status: self.status,
+                payload: HttpResponsePayload::Bytes {
+                    body: Bytes::from(body),
+                    content_type: ContentType::json().to_string(),
+                },
+                headers: self.headers.clone(),
+            },
+            Err(_) => {
+                panic!("Failed to serialize response body");
+            }
+        }
+    }
+
+    /// Serialize the value as JSON and set an `ETag` computed from the body.
+    /// When `weak` is true a weak validator (`W/"..."`) is emitted instead of a strong one.
+    /// If the request `If-None-Match` matches the ETag, a `304 Not Modified` without body is returned.
+    pub fn json_with_etag<T>(&self, req: &HttpRequest, value: T, weak: bool) -> HttpResponse
+    where
+        T: Serialize + 'static,
+    {
+        let body = match serde_json::to_value(&value) {
+            Ok(body) => body,
+            Err(_) => {
+                panic!("Failed to serialize response body");
+            }
+        };
+
+        let etag = etag_for(&body, weak);
+        let mut headers = self.headers.clone();
+        headers.push((ETAG.to_string(), etag.clone()));
+
+        if if_none_match(req, &etag) {
+            return HttpResponse {
+                status: StatusCode::NOT_MODIFIED,
+                payload: HttpResponsePayload::Empty,
+                headers,
+            };
+        }
+
+        HttpResponse {
+            status: self.status,
+            payload: HttpResponsePayload::Json(body),
+            headers,
+        }
+    }
+}
+
+/// A 128-bit SHA-256 prefix of the body, which unlike `DefaultHasher` is stable across
+/// Rust releases, so instances built with different toolchains agree on the ETag.
+fn etag_for(body: &serde_json::Value, weak: bool) -> String {
+    let digest = Sha256::digest(body.to_string().as_bytes());
+    let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    let tag = format!("\"{}\"", hex);
+    if weak {
+        format!("W/{}", tag)
+    } else {
+        tag
+    }
+}
+
+/// Weak comparison of `If-None-Match` against the given ETag, as required by RFC 7232.
+fn if_none_match(req: &HttpRequest, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+
+    req.headers()
+        .get_all(IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+#[cfg(all(debug_assertions, feature = "jsonschema"))]
+fn check_schema<T: JsonSchema>(body: &serde_json::Value) {
+    let schema = match serde_json::to_value(schemars::schema_for!(T)) {
+        Ok(schema) => schema,
+        Err(e) => {
+            tracing::warn!("Failed to serialize the {} schema: {}", T::schema_name(), e);
+            return;
+        }
+    };
+    match jsonschema::validator_for(&schema) {
+        Ok(validator) => {
+            for error in validator.iter_errors(body) {
+                tracing::warn!(
+                    "Response body does not match the {} schema: {}",
+                    T::schema_name(),
+                    error
+                );
+            }
+        }
+        Err(e) => tracing::warn!("Invalid {} schema: {}", T::schema_name(), e),
+    }
+}
+
+/// The body the error response of the error would have, as a JSON value.
+fn error_frame(e: &HttpError) -> serde_json::Value {
+    let body = error::ResponseError::error_response(e).into_body();
+    match actix_web::body::MessageBody::try_into_bytes(body) {
+        Ok(body) => serde_json::from_slice(&body)
+            .unwrap_or_else(|_| serde_json::Value::String(e.to_string())),
+        Err(_) => serde_json::Value::String(e.to_string()),
+    }
+}
+
+fn file_error(e: std::io::Error) -> HttpError {
+    tracing::error!(error = %e, "failed to read file");
+    HttpError::with_status(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read file")
+}
+
+pub(crate) fn push_server_timing(
+    headers: &mut Vec<(String, String)>,
+    name: &str,
+    duration: Duration,
+) {
+    let metric = format!("{};dur={:.1}", name, duration.as_secs_f64() * 1000.0);
+    match headers
+        .iter_mut()
+        .find(|(k, _)| k.eq_ignore_ascii_case("server-timing"))
+    {
+        Some((_, value)) => {
+            value.push_str(", ");
+            value.push_str(&metric);
+        }
+        None => headers.push(("Server-Timing".to_string(), metric)),
+    }
+}
+
+fn content_disposition(disposition: DispositionType, filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut parameters = vec![DispositionParam::Filename(fallback)];
+    if !filename.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition {
+        disposition,
+        parameters,
+    }
+    .to_string()
+}
+
+fn strip_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.values_mut().for_each(strip_nulls);
+            map.retain(|_, v| match v {
+                serde_json::Value::Null => false,
+                serde_json::Value::Object(inner) => !inner.is_empty(),
+                _ => true,
+            });
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
 }
 
 
+type ResponseHook = Box<dyn Fn(&mut HttpResponse) + Send + Sync>;
+
+static RESPONSE_HOOK: OnceLock<ResponseHook> = OnceLock::new();
+
+/// Register a hook transforming every `HttpResponse`, e.g. to add a build-version header.
+/// It can only be set once, usually at startup; later calls are ignored.
+/// The hook runs in `respond_to` before the actix response is built, and before an `Audited` sink
+/// so the sink records the final response. It may run concurrently on every worker thread.
+/// Error responses do not go through it.
+pub fn set_response_hook(hook: impl Fn(&mut HttpResponse) + Send + Sync + 'static) {
+    let _ = RESPONSE_HOOK.set(Box::new(hook));
+}
+
 impl Responder for HttpResponse {
     type Body = BoxBody;
 
-    fn respond_to(self, _req: &HttpRequest) -> actix_web::HttpResponse<Self::Body> {
+    fn respond_to(mut self, _req: &HttpRequest) -> actix_web::HttpResponse<Self::Body> {
+        self.finalize();
+        self.into_actix()
+    }
+}
+
+impl HttpResponse {
+    /// Run the response hook and add the missing default headers, giving the response as sent.
+    pub(crate) fn finalize(&mut self) {
+        if let Some(hook) = RESPONSE_HOOK.get() {
+            hook(self);
+        }
+
+        let defaults = DEFAULT_HEADERS
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (key, value) in defaults.iter() {
+            let valid = HeaderName::try_from(key.as_str()).is_ok()
+                && HeaderValue::try_from(value.as_str()).is_ok();
+            let missing = !self
+                .headers
+                .iter()
+                .any(|(k, _)| k.eq_ignore_ascii_case(key));
+            if valid && missing {
+                self.headers.push((key.clone(), value.clone()));
+            }
+        }
+    }
+
+    /// Build the actix response of a finalized response.
+    pub(crate) fn into_actix(self) -> actix_web::HttpResponse<BoxBody> {
         let mut http_response_builder = actix_web::HttpResponse::build(self.status);
 
+        // Repeated names, e.g. relayed `Set-Cookie` headers, are all sent.
+        let mut names = HashSet::new();
         for (key, value) in self.headers {
-            http_response_builder.insert_header((key, value));
+            if names.insert(key.to_ascii_lowercase()) {
+                http_response_builder.insert_header((key, value));
+            } else {
+                http_response_builder.append_header((key, value));
+            }
         }
 
         match self.payload {
             HttpResponsePayload::Json(value) => http_response_builder
                 .content_type("application/json")
                 .json(value),
+            HttpResponsePayload::Bytes { body, content_type } => {
+                http_response_builder.content_type(content_type).body(body)
+            }
+            HttpResponsePayload::Stream { body, length } => {
+                if let Some(length) = length {
+                    http_response_builder.no_chunking(length);
+                }
+                http_response_builder.streaming(body)
+            }
             HttpResponsePayload::Empty => http_response_builder.finish(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct ValidationErrorResponse {
+    #[schemars(with = "Vec<ValidationErrorSchema>")]
     pub validation_errors: Vec<ValidationError>,
 }
 
+// The OpenAPI schema of a `validator::ValidationError`, which has no `JsonSchema` impl.
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct ValidationErrorSchema {
+    code: String,
+    message: Option<String>,
+    params: BTreeMap<String, serde_json::Value>,
+}
+
 impl ValidationErrorResponse {
+    /// The errors of all the fields, including those of nested structs and lists,
+    /// each with the path of its field in the `field` param, e.g. `address.city` or `items[0].name`.
     pub fn from(validation_errors: ValidationErrors) -> ValidationErrorResponse {
-        let validation_errors = validation_errors
-            .field_errors()
-            .into_values()
-            .flat_map(|v| v.clone())
-            .collect();
+        let mut errors = vec![];
+        collect_validation_errors(validation_errors, "", &mut errors);
+        ValidationErrorResponse {
+            validation_errors: errors,
+        }
+    }
+
+    /// Append the errors of another validation source, e.g. the query params after the body.
+    pub fn merge(mut self, other: ValidationErrors) -> ValidationErrorResponse {
+        self.validation_errors
+            .extend(ValidationErrorResponse::from(other).validation_errors);
+        self
+    }
+
+    /// The messages of all the errors as a flat list, for clients not handling the structured shape.
+    /// Errors without a message fall back to their code.
+    pub fn to_flat_response(&self) -> FlatValidationErrorResponse {
+        FlatValidationErrorResponse {
+            errors: self
+                .validation_errors
+                .iter()
+                .map(|error| match &error.message {
+                    Some(message) => message.to_string(),
+                    None => error.code.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// A single `deserialize` error for a body that could not be deserialized,
+    /// with the path of the offending field in the `field` param when known.
+    pub fn deserialize_error(
+        field: Option<&str>,
+        message: impl Into<String>,
+    ) -> ValidationErrorResponse {
+        let mut error = ValidationError::new("deserialize");
+        error.message = Some(message.into().into());
+        if let Some(field) = field {
+            error.add_param("field".into(), &field);
+        }
+        ValidationErrorResponse {
+            validation_errors: vec![error],
+        }
+    }
+
+    /// Combine the errors of several validation sources into a single response.
+    pub fn from_many(
+        sources: impl IntoIterator<Item = ValidationErrors>,
+    ) -> ValidationErrorResponse {
+        sources.into_iter().fold(
+            ValidationErrorResponse {
+                validation_errors: vec![],
+            },
+            ValidationErrorResponse::merge,
+        )
+    }
+}
+
+fn collect_validation_errors(
+    validation_errors: ValidationErrors,
+    prefix: &str,
+    errors: &mut Vec<ValidationError>,
+) {
+    let mut fields: Vec<_> = validation_errors.into_errors().into_iter().collect();
+    fields.sort_by_key(|(field, _)| *field);
+    for (field, kind) in fields {
+        let path = if prefix.is_empty() {
+            field.to_string()
+        } else {
+            format!("{}.{}", prefix, field)
+        };
+        match kind {
+            ValidationErrorsKind::Field(field_errors) => {
+                for mut error in field_errors {
+                    error.add_param("field".into(), &path);
+                    errors.push(error);
+                }
+            }
+            ValidationErrorsKind::Struct(nested) => {
+                collect_validation_errors(*nested, &path, errors);
+            }
+            ValidationErrorsKind::List(items) => {
+                for (index, nested) in items {
+                    collect_validation_errors(*nested, &format!("{}[{}]", path, index), errors);
+                }
+            }
+        }
+    }
+}
+
+/// The flat validation error shape: `{ "errors": ["Email is required", ...] }`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FlatValidationErrorResponse {
+    pub errors: Vec<String>,
+}
+
+static FLAT_VALIDATION_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Whether validation errors are returned with the flat `FlatValidationErrorResponse` shape.
+/// Disabled by default; for a single endpoint, respond with `to_flat_response` instead.
+pub fn set_flat_validation_errors(enabled: bool) {
+    FLAT_VALIDATION_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
+static UNPROCESSABLE_VALIDATION_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Whether validation errors are returned as 422 instead of 400, for API styles reserving 400
+/// for malformed requests. Disabled by default. The OpenAPI documentation follows it,
+/// so set it before generating the documentation.
+pub fn set_unprocessable_validation_errors(enabled: bool) {
+    UNPROCESSABLE_VALIDATION_ERRORS.store(enabled, Ordering::Relaxed);
+}
 
-        ValidationErrorResponse { validation_errors }
+fn validation_error_status() -> StatusCode {
+    if UNPROCESSABLE_VALIDATION_ERRORS.load(Ordering::Relaxed) {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        StatusCode::BAD_REQUEST
     }
 }
 
@@ -126,64 +845,547 @@ impl Display for ValidationErrorResponse {
     }
 }
 
+/// A page of items along with the total number of items available.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct HttpErrorDetailsResponse {
-    pub message: String,
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub total: i64,
 }
 
-#[derive(Debug, Display, ApiErrorComponent)]
-#[openapi_error(
-    status(code = 500),
-    status(code = 400),
-)]
-pub enum HttpError {
-    DatabaseError(sqlx::Error),
-    ValidationError(ValidationErrorResponse),
-    WithDetails(HttpErrorDetails),
+/// The result of one item of a batch operation, reported by `HttpResponse::multi_status`
+/// as `{ "status": 201, "body": ... }`.
+#[derive(Debug, Clone)]
+pub struct SubResult {
+    pub status: StatusCode,
+    pub body: serde_json::Value,
 }
 
-impl Error for HttpError {}
-
-impl error::ResponseError for HttpError {
-    fn status_code(&self) -> StatusCode {
-        match self {
-            HttpError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            HttpError::ValidationError(_) => StatusCode::BAD_REQUEST,
-            HttpError::WithDetails(details) => details.status_code,
+impl SubResult {
+    pub fn new(status: StatusCode, body: impl Serialize) -> SubResult {
+        match serde_json::to_value(body) {
+            Ok(body) => SubResult { status, body },
+            Err(_) => {
+                panic!("Failed to serialize sub result body");
+            }
         }
     }
 
-    fn error_response(&self) -> actix_web::HttpResponse {
-        let mut http_response_builder = actix_web::HttpResponse::build(self.status_code());
-        http_response_builder.insert_header(ContentType::json());
+    pub fn ok(body: impl Serialize) -> SubResult {
+        SubResult::new(StatusCode::OK, body)
+    }
 
-        match self {
-            HttpError::DatabaseError(er) => http_response_builder.json(HttpErrorDetailsResponse {
-                message: er.to_string(),
-            }),
-            HttpError::ValidationError(er) => http_response_builder.json(er),
-            HttpError::WithDetails(details) => {
-                for (key, value) in details.headers.iter() {
-                    http_response_builder.insert_header((key.clone(), value.clone()));
-                }
-                http_response_builder.json(HttpErrorDetailsResponse {
-                    message: details.message.clone(),
-                })
-            }
-        }
+    /// A failed item, with the same `{ "message": ... }` body as a `WithDetails` error.
+    pub fn error(status: StatusCode, message: impl Into<String>) -> SubResult {
+        SubResult::new(
+            status,
+            HttpErrorDetailsResponse {
+                message: message.into(),
+            },
+        )
     }
 }
 
-impl From<ValidationErrors> for HttpError {
-    fn from(validation_errors: ValidationErrors) -> Self {
-        HttpError::ValidationError(ValidationErrorResponse::from(validation_errors))
+impl Serialize for SubResult {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("status", &self.status.as_u16())?;
+        map.serialize_entry("body", &self.body)?;
+        map.end()
     }
 }
 
-impl From<sqlx::Error> for HttpError {
-    fn from(e: sqlx::Error) -> Self {
-        HttpError::DatabaseError(e)
-    }
+/// A page of items along with the cursor of the next page, `None` on the last page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CursorPage<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+/// A HAL link object, `{ "href": ... }`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HalLink {
+    pub href: String,
+}
+
+impl HalLink {
+    pub fn new(href: impl Into<String>) -> HalLink {
+        HalLink { href: href.into() }
+    }
+}
+
+/// A HAL resource: the resource fields at the top level, along with the `_links`
+/// and `_embedded` members. `_embedded` is omitted when there is nothing embedded.
+#[derive(Debug, Clone, Serialize)]
+pub struct Hal<T> {
+    #[serde(flatten)]
+    pub resource: T,
+    #[serde(rename = "_links")]
+    pub links: BTreeMap<String, HalLink>,
+    #[serde(rename = "_embedded", skip_serializing_if = "BTreeMap::is_empty")]
+    pub embedded: BTreeMap<String, serde_json::Value>,
+}
+
+/// A decimal serialized as a string with exactly `SCALE` decimal places, e.g. `MoneyJson::<2>(amount)`
+/// for `"10.50"`, so monetary values never go through a float on the client.
+/// Use it as a response DTO field, `json` serializes it like any other. Values are rounded
+/// half away from zero, and only strings are accepted when deserializing. The serialization does not depend on the `rust_decimal`
+/// serde features, which only apply to fields of the plain `Decimal` type.
+#[cfg(feature = "rust_decimal")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoneyJson<const SCALE: u32>(pub rust_decimal::Decimal);
+
+#[cfg(feature = "rust_decimal")]
+impl<const SCALE: u32> Serialize for MoneyJson<SCALE> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = self
+            .0
+            .round_dp_with_strategy(SCALE, rust_decimal::RoundingStrategy::MidpointAwayFromZero);
+        serializer.serialize_str(&format!("{:.*}", SCALE as usize, value))
+    }
+}
+
+#[cfg(feature = "rust_decimal")]
+impl<'de, const SCALE: u32> Deserialize<'de> for MoneyJson<SCALE> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value
+            .parse::<rust_decimal::Decimal>()
+            .map(MoneyJson)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "rust_decimal")]
+impl<const SCALE: u32> JsonSchema for MoneyJson<SCALE> {
+    fn schema_name() -> String {
+        "MoneyJson".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HttpErrorDetailsResponse {
+    pub message: String,
+}
+
+#[derive(Debug, Display)]
+pub enum HttpError {
+    DatabaseError(sqlx::Error),
+    ValidationError(ValidationErrorResponse),
+    /// A request that could not be deserialized, e.g. invalid JSON syntax or a field of the wrong type.
+    /// It has the same body as a `ValidationError`, usually a `deserialize_error`, but is always a 400,
+    /// even when validation errors are returned as 422.
+    Malformed(ValidationErrorResponse),
+    WithDetails(HttpErrorDetails),
+    /// An error emitting exactly the given body, see `HttpError::passthrough`.
+    WithBody(HttpErrorBody),
+    /// An error with a JSON body that is nested under the error envelope key like the other errors,
+    /// see `HttpError::conflict_with_body`.
+    WithEnvelopedBody(HttpErrorBody),
+    /// Several errors reported at once, e.g. every invalid item of a batch, see `HttpError::multiple`.
+    #[display("{} errors", _0.len())]
+    Multiple(Vec<HttpError>),
+}
+
+impl Error for HttpError {}
+
+/// Status codes documented for `HttpError`, with the schema of their body:
+/// `ValidationErrorResponse` for 400, used by malformed requests, and for 422 when validation errors
+/// are returned as 422, `HttpErrorDetailsResponse` otherwise.
+const DOCUMENTED_ERROR_STATUS_CODES: [StatusCode; 3] = [
+    StatusCode::INTERNAL_SERVER_ERROR,
+    StatusCode::BAD_REQUEST,
+    StatusCode::UNPROCESSABLE_ENTITY,
+];
+
+fn has_validation_error_schema(status: StatusCode) -> bool {
+    status == StatusCode::BAD_REQUEST || status == validation_error_status()
+}
+
+fn error_schema_name(status: StatusCode) -> String {
+    if has_validation_error_schema(status) {
+        ValidationErrorResponse::schema_name()
+    } else {
+        HttpErrorDetailsResponse::schema_name()
+    }
+}
+
+fn error_schema<T: JsonSchema>() -> ReferenceOr<Schema> {
+    let mut generator = SchemaSettings::openapi3()
+        .with(|settings| settings.inline_subschemas = true)
+        .into_generator();
+    ReferenceOr::Object(Schema::Object(generator.root_schema_for::<T>().schema))
+}
+
+impl ApiErrorComponent for HttpError {
+    fn schemas_by_status_code() -> BTreeMap<String, (String, ReferenceOr<Schema>)> {
+        DOCUMENTED_ERROR_STATUS_CODES
+            .iter()
+            .map(|status| {
+                let schema = if has_validation_error_schema(*status) {
+                    error_schema::<ValidationErrorResponse>()
+                } else {
+                    error_schema::<HttpErrorDetailsResponse>()
+                };
+                (
+                    status.as_str().to_string(),
+                    (error_schema_name(*status), schema),
+                )
+            })
+            .collect()
+    }
+
+    fn error_responses() -> Vec<(String, apistos::paths::Response)> {
+        DOCUMENTED_ERROR_STATUS_CODES
+            .iter()
+            .map(|status| {
+                let media_type = MediaType {
+                    schema: Some(ReferenceOr::Reference {
+                        _ref: format!("#/components/schemas/{}", error_schema_name(*status)),
+                    }),
+                    ..Default::default()
+                };
+                let response = apistos::paths::Response {
+                    description: status.canonical_reason().unwrap_or_default().to_string(),
+                    content: BTreeMap::from([("application/json".to_string(), media_type)]),
+                    ..Default::default()
+                };
+                (status.as_str().to_string(), response)
+            })
+            .collect()
+    }
+}
+
+// HttpError must stay boxable as `Box<dyn Error + Send + Sync>` for thiserror/anyhow interop.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<HttpError>();
+};
+
+impl HttpError {
+    /// A `WithDetails` error with the given status and message.
+    /// The message may also be a key of the registered `MessageCatalog`, see `HttpError::localize`.
+    pub fn with_status(status_code: StatusCode, message: impl Into<String>) -> HttpError {
+        HttpError::WithDetails(HttpErrorDetails {
+            message: message.into(),
+            status_code,
+            headers: vec![],
+        })
+    }
+
+    /// An error emitting exactly the given status and JSON body, e.g. to relay an upstream error as is.
+    pub fn passthrough(status_code: StatusCode, body: serde_json::Value) -> HttpError {
+        HttpError::WithBody(HttpErrorBody {
+            status_code,
+            body,
+            headers: vec![],
+        })
+    }
+
+    /// Report several errors at once, as a JSON array of `{ "status": ..., "body": ... }` items
+    /// holding the status and body each error would have on its own. The response status is the
+    /// highest server error status among them, or 400 when all of them are client errors.
+    /// With an error envelope key set, the array is nested under it, not the items.
+    /// An empty list is a 400 with an empty array, so only build one once there are errors.
+    pub fn multiple(errors: Vec<HttpError>) -> HttpError {
+        HttpError::Multiple(errors)
+    }
+
+    /// A 409 with the message along with the conflicting resource, for clients to reconcile
+    /// without a follow-up GET: `{ "message": ..., "existing": ... }`, nested under the error
+    /// envelope key when one is set.
+    pub fn conflict_with_body<T: Serialize>(message: impl Into<String>, existing: T) -> HttpError {
+        HttpError::WithEnvelopedBody(HttpErrorBody {
+            status_code: StatusCode::CONFLICT,
+            body: serde_json::json!({ "message": message.into(), "existing": existing }),
+            headers: vec![],
+        })
+    }
+
+    /// A 405 with the `Allow` header listing the permitted methods.
+    pub fn method_not_allowed(allowed: &[Method]) -> HttpError {
+        let allow = allowed
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+        HttpError::WithDetails(HttpErrorDetails {
+            message: "Method not allowed".to_string(),
+            status_code: StatusCode::METHOD_NOT_ALLOWED,
+            headers: vec![(ALLOW.to_string(), allow)],
+        })
+    }
+}
+
+impl error::ResponseError for HttpError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            HttpError::DatabaseError(er) => database_error_status(er),
+            HttpError::ValidationError(_) => validation_error_status(),
+            HttpError::Malformed(_) => StatusCode::BAD_REQUEST,
+            HttpError::WithDetails(details) => details.status_code,
+            HttpError::WithBody(error) | HttpError::WithEnvelopedBody(error) => error.status_code,
+            HttpError::Multiple(errors) => multiple_status_code(errors),
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        let mut http_response_builder = actix_web::HttpResponse::build(self.status_code());
+        http_response_builder.insert_header(ContentType::json());
+
+        let mut response = match self {
+            HttpError::DatabaseError(er) => {
+                if SQLSTATE_HEADER.load(Ordering::Relaxed) {
+                    if let Some(code) = sqlstate(er) {
+                        http_response_builder.insert_header(("X-DB-SQLSTATE", code));
+                    }
+                }
+                error_body(
+                    &mut http_response_builder,
+                    ErrorEnvelope(&HttpErrorDetailsResponse {
+                        message: database_error_message(er),
+                    }),
+                )
+            }
+            HttpError::ValidationError(er) | HttpError::Malformed(er)
+                if FLAT_VALIDATION_ERRORS.load(Ordering::Relaxed) =>
+            {
+                error_body(
+                    &mut http_response_builder,
+                    ErrorEnvelope(&er.to_flat_response()),
+                )
+            }
+            HttpError::ValidationError(er) | HttpError::Malformed(er) => {
+                error_body(&mut http_response_builder, ErrorEnvelope(er))
+            }
+            HttpError::WithDetails(details) => {
+                for (key, value) in details.headers.iter() {
+                    http_response_builder.insert_header((key.clone(), value.clone()));
+                }
+                error_body(
+                    &mut http_response_builder,
+                    ErrorEnvelope(&HttpErrorDetailsResponse {
+                        message: details.message.clone(),
+                    }),
+                )
+            }
+            HttpError::WithBody(error) => {
+                for (key, value) in error.headers.iter() {
+                    http_response_builder.insert_header((key.clone(), value.clone()));
+                }
+                error_body(&mut http_response_builder, &error.body)
+            }
+            HttpError::WithEnvelopedBody(error) => {
+                for (key, value) in error.headers.iter() {
+                    http_response_builder.insert_header((key.clone(), value.clone()));
+                }
+                error_body(&mut http_response_builder, ErrorEnvelope(&error.body))
+            }
+            HttpError::Multiple(errors) => {
+                let results = errors
+                    .iter()
+                    .map(|error| SubResult::new(error.status_code(), error_item_body(error)))
+                    .collect::<Vec<SubResult>>();
+                error_body(&mut http_response_builder, ErrorEnvelope(&results))
+            }
+        };
+        apply_default_headers(&mut response);
+        response
+    }
+}
+
+/// The body of an item of a `Multiple` error, or of the `{"error": ...}` frame ending a stream,
+/// without the error envelope since the array or the frame already nests it under a key.
+fn error_item_body(error: &HttpError) -> serde_json::Value {
+    let body = error_frame(error);
+    match (ERROR_ENVELOPE_KEY.get(), error) {
+        (_, HttpError::WithBody(_)) => body,
+        (Some(key), _) => match body {
+            serde_json::Value::Object(mut map) if map.len() == 1 && map.contains_key(key) => {
+                map.remove(key).unwrap_or_default()
+            }
+            body => body,
+        },
+        (None, _) => body,
+    }
+}
+
+/// The most severe status of the errors, which is the highest server error status,
+/// or 400 when all of them are client errors.
+fn multiple_status_code(errors: &[HttpError]) -> StatusCode {
+    errors
+        .iter()
+        .map(error::ResponseError::status_code)
+        .filter(|status| status.is_server_error())
+        .max()
+        .unwrap_or(StatusCode::BAD_REQUEST)
+}
+
+static PRETTY_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Whether error bodies are pretty-printed, for reading them with curl while developing.
+/// Disabled by default, keep it disabled in production.
+pub fn set_pretty_errors(enabled: bool) {
+    PRETTY_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
+fn error_body(
+    http_response_builder: &mut actix_web::HttpResponseBuilder,
+    body: impl Serialize,
+) -> actix_web::HttpResponse {
+    let body = if PRETTY_ERRORS.load(Ordering::Relaxed) {
+        serde_json::to_string_pretty(&body)
+    } else {
+        serde_json::to_string(&body)
+    };
+    match body {
+        Ok(body) => http_response_builder.body(body),
+        Err(e) => actix_web::HttpResponse::from_error(e),
+    }
+}
+
+static ERROR_ENVELOPE_KEY: OnceLock<String> = OnceLock::new();
+
+/// Nest error bodies under the given key, e.g. `error` for `{ "error": { "message": ... } }`.
+/// By default error bodies are not nested. `HttpError::passthrough` bodies are always sent as is.
+/// Only the first call has an effect, later calls are ignored.
+pub fn set_error_envelope_key(key: impl Into<String>) {
+    let _ = ERROR_ENVELOPE_KEY.set(key.into());
+}
+
+struct ErrorEnvelope<'a, T>(&'a T);
+
+impl<T: Serialize> Serialize for ErrorEnvelope<'_, T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match ERROR_ENVELOPE_KEY.get() {
+            Some(key) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(key, self.0)?;
+                map.end()
+            }
+            None => self.0.serialize(serializer),
+        }
+    }
+}
+
+static DETAILED_ERRORS: AtomicBool = AtomicBool::new(true);
+
+/// Whether database error responses include the underlying error message.
+/// Enabled by default, disable it in production to only return a generic message.
+pub fn set_detailed_errors(enabled: bool) {
+    DETAILED_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
+static SQLSTATE_HEADER: AtomicBool = AtomicBool::new(false);
+
+/// Whether database error responses carry the SQLSTATE code, e.g. `23505`, in a `X-DB-SQLSTATE` header.
+/// Disabled by default, only enable it outside production to correlate failures with constraints.
+pub fn set_sqlstate_header(enabled: bool) {
+    SQLSTATE_HEADER.store(enabled, Ordering::Relaxed);
+}
+
+const CHECK_VIOLATION: &str = "23514";
+
+pub(crate) fn sqlstate(er: &sqlx::Error) -> Option<String> {
+    match er {
+        sqlx::Error::Database(db) => db.code().map(|code| code.to_string()),
+        _ => None,
+    }
+}
+
+/// The status of a database error, mapped from its SQLSTATE code.
+fn database_error_status(er: &sqlx::Error) -> StatusCode {
+    match sqlstate(er).as_deref() {
+        Some(CHECK_VIOLATION) => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn database_error_message(er: &sqlx::Error) -> String {
+    if let sqlx::Error::Database(db) = er {
+        if db.code().as_deref() == Some(CHECK_VIOLATION) {
+            return match db.constraint() {
+                Some(constraint) => format!("Check constraint violated: {}", constraint),
+                None => "Check constraint violated".to_string(),
+            };
+        }
+    }
+
+    let message = match er {
+        sqlx::Error::ColumnDecode { index, source } => {
+            tracing::error!(column = %index, error = %source, "failed to decode column");
+            format!("Failed to decode column {}: {}", index, source)
+        }
+        er => er.to_string(),
+    };
+
+    if DETAILED_ERRORS.load(Ordering::Relaxed) {
+        message
+    } else {
+        "Internal server error".to_string()
+    }
+}
+
+impl From<ValidationErrors> for HttpError {
+    fn from(validation_errors: ValidationErrors) -> Self {
+        HttpError::ValidationError(ValidationErrorResponse::from(validation_errors))
+    }
+}
+
+/// Bodies that can not be deserialized become a `Malformed` error, so malformed and invalid input
+/// share one shape; other JSON payload errors keep their status. Register it with
+/// `web::JsonConfig::default().error_handler(|e, _| HttpError::from(e).into())`.
+impl From<error::JsonPayloadError> for HttpError {
+    fn from(e: error::JsonPayloadError) -> Self {
+        match e {
+            error::JsonPayloadError::Deserialize(e) => HttpError::Malformed(
+                ValidationErrorResponse::deserialize_error(None, e.to_string()),
+            ),
+            e => HttpError::with_status(error::ResponseError::status_code(&e), e.to_string()),
+        }
+    }
+}
+
+impl From<sqlx::Error> for HttpError {
+    fn from(e: sqlx::Error) -> Self {
+        HttpError::DatabaseError(e)
+    }
+}
+
+#[cfg(feature = "migrate")]
+impl From<sqlx::migrate::MigrateError> for HttpError {
+    fn from(e: sqlx::migrate::MigrateError) -> Self {
+        HttpError::with_status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+/// Upstream timeouts map to 504 and any other upstream failure to 502, with a generic message:
+/// the error is logged, but never sent to the client since it includes the upstream URL.
+#[cfg(feature = "reqwest")]
+impl From<reqwest::Error> for HttpError {
+    fn from(e: reqwest::Error) -> Self {
+        tracing::error!(error = %e, "upstream request failed");
+        if e.is_timeout() {
+            HttpError::with_status(StatusCode::GATEWAY_TIMEOUT, "Upstream request timed out")
+        } else {
+            HttpError::with_status(StatusCode::BAD_GATEWAY, "Upstream request failed")
+        }
+    }
+}
+
+/// Token errors map to a 401 with a generic message, never leaking why the token was rejected.
+#[cfg(feature = "jsonwebtoken")]
+impl From<jsonwebtoken::errors::Error> for HttpError {
+    fn from(_: jsonwebtoken::errors::Error) -> Self {
+        HttpError::WithDetails(HttpErrorDetails {
+            message: "Invalid token".to_string(),
+            status_code: StatusCode::UNAUTHORIZED,
+            headers: vec![(WWW_AUTHENTICATE.to_string(), "Bearer".to_string())],
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -209,6 +1411,20 @@ impl Display for HttpErrorDetails {
     }
 }
 
+/// An error responding with an arbitrary JSON body.
+#[derive(Debug, Clone)]
+pub struct HttpErrorBody {
+    pub status_code: StatusCode,
+    pub body: serde_json::Value,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Display for HttpErrorBody {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.status_code, self.body)
+    }
+}
+
 macro_rules! http_response_builder {
     ($name:ident,$status:expr) => {
         impl HttpResponse {
@@ -223,6 +1439,8 @@ macro_rules! http_response_builder {
 http_response_builder!(BadRequest, StatusCode::BAD_REQUEST);
 http_response_builder!(Ok, StatusCode::OK);
 http_response_builder!(Created, StatusCode::CREATED);
+http_response_builder!(Accepted, StatusCode::ACCEPTED);
+http_response_builder!(NoContent, StatusCode::NO_CONTENT);
 http_response_builder!(NotFound, StatusCode::NOT_FOUND);
 
 macro_rules! http_error {
@@ -248,6 +1466,10 @@ http_error!(not_found, StatusCode::NOT_FOUND);
 
 http_error!(internal_server_error, StatusCode::INTERNAL_SERVER_ERROR);
 
+http_error!(bad_gateway, StatusCode::BAD_GATEWAY);
+
+http_error!(gateway_timeout, StatusCode::GATEWAY_TIMEOUT);
+
 macro_rules! http_response {
     ($name:ident,$status:ident) => {
         #[allow(non_snake_case, missing_docs)]
@@ -258,3 +1480,714 @@ macro_rules! http_response {
 }
 
 http_response!(ok, Ok);
+
+/// Headers never relayed by `HttpResponse::from_reqwest`: the hop-by-hop ones,
+/// plus `Content-Length` which is set again for the buffered body.
+#[cfg(feature = "reqwest")]
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+#[cfg(feature = "reqwest")]
+impl HttpResponse {
+    /// Relay an upstream response with its status, headers and buffered body, for proxy endpoints.
+    /// Hop-by-hop headers such as `Connection` and `Transfer-Encoding` are stripped, while repeated
+    /// headers such as `Set-Cookie` are all relayed.
+    /// Failing to read the body is a 502, or a 504 on timeout.
+    pub async fn from_reqwest(response: reqwest::Response) -> Response {
+        let status =
+            StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+        let mut content_type = "application/octet-stream".to_string();
+        let mut headers = vec![];
+        for (key, value) in response.headers() {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            if key.as_str() == "content-type" {
+                content_type = value.to_string();
+            } else if !HOP_BY_HOP_HEADERS.contains(&key.as_str()) {
+                headers.push((key.to_string(), value.to_string()));
+            }
+        }
+        let body = response.bytes().await?;
+
+        Ok(HttpResponse {
+            status,
+            payload: HttpResponsePayload::Bytes { body, content_type },
+            headers,
+        })
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl HttpResponse {
+    /// A 200 streaming the output of a Postgres `COPY ... TO STDOUT` statement run on the transaction.
+    /// The transaction is moved to a spawned task that keeps it open while the output is streamed,
+    /// and commits it once done. A failing statement results in an error response,
+    /// while an error after the first chunk aborts the stream and rolls the transaction back.
+    pub async fn copy_out(
+        mut tx: sqlx::Transaction<'static, sqlx::Postgres>,
+        copy_sql: impl Into<String>,
+        content_type: impl Into<String>,
+    ) -> Response {
+        let copy_sql = copy_sql.into();
+        let (started_sender, started) = oneshot::channel();
+        let (mut sender, receiver) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            let completed = {
+                let mut stream = match tx.copy_out_raw(&copy_sql).await {
+                    Ok(stream) => {
+                        let _ = started_sender.send(Ok(()));
+                        stream
+                    }
+                    Err(e) => {
+                        let _ = started_sender.send(Err(e));
+                        return;
+                    }
+                };
+                loop {
+                    match stream.next().await {
+                        Some(Ok(chunk)) => {
+                            if sender.send(Ok(chunk)).await.is_err() {
+                                break false;
+                            }
+                        }
+                        Some(Err(e)) => {
+                            let _ = sender.send(Err(HttpError::from(e))).await;
+                            break false;
+                        }
+                        None => break true,
+                    }
+                }
+            };
+
+            let res = if completed {
+                tx.commit().await
+            } else {
+                crate::tx::rollback(tx).await
+            };
+            if let Err(e) = res {
+                let _ = sender.send(Err(HttpError::from(e))).await;
+            }
+        });
+
+        match started.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return internal_server_error("COPY task ended unexpectedly"),
+        }
+
+        Ok(HttpResponse {
+            status: StatusCode::OK,
+            payload: HttpResponsePayload::Stream {
+                body: receiver.boxed(),
+                length: None,
+            },
+            headers: vec![(CONTENT_TYPE.to_string(), content_type.into())],
+        })
+    }
+}
+
+impl HttpResponse {
+    /// A 202 Accepted pointing to a status endpoint through the `Location` header.
+    /// The optional body usually describes the submitted job.
+    pub fn accepted_at<T>(location: impl Into<String>, body: Option<T>) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        let location = location.into();
+        if location.trim().is_empty() {
+            return bad_request("Location must not be empty");
+        }
+
+        let builder = HttpResponse::Accepted().add_header(LOCATION.to_string(), location);
+        match body {
+            Some(body) => Ok(builder.json(body)),
+            None => Ok(builder.finish()),
+        }
+    }
+
+    /// A 200 streaming the file at the given path with the given content type.
+    /// The file is never fully read into memory; `Content-Length` is set from the file metadata.
+    /// A missing file, or a path that is not a regular file such as a directory, results in a 404.
+    /// Other io errors are logged and result in a 500 with a generic message, never the OS message.
+    pub async fn file(path: impl AsRef<Path>, content_type: impl Into<String>) -> Response {
+        let file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return not_found("File not found");
+            }
+            Err(e) => return Err(file_error(e)),
+        };
+        let metadata = file.metadata().await.map_err(file_error)?;
+        if !metadata.is_file() {
+            return not_found("File not found");
+        }
+
+        let body = tokio_util::io::ReaderStream::new(file)
+            .map_err(file_error)
+            .boxed();
+
+        Ok(HttpResponse {
+            status: StatusCode::OK,
+            payload: HttpResponsePayload::Stream {
+                body,
+                length: Some(metadata.len()),
+            },
+            headers: vec![(CONTENT_TYPE.to_string(), content_type.into())],
+        })
+    }
+
+    /// A 200 with an explicit JSON `null` body, for clients that can not handle an empty body.
+    pub fn ok_null() -> Response {
+        Ok(HttpResponse::Ok().json(serde_json::Value::Null))
+    }
+
+    /// A 200 with an empty JSON object `{}` body, for clients that parse every body as JSON.
+    pub fn ok_empty_object() -> Response {
+        Ok(HttpResponse::Ok().json(serde_json::Value::Object(serde_json::Map::new())))
+    }
+
+    /// Collect a `Stream` payload of unknown length into memory so it is sent with a `Content-Length`,
+    /// for proxies that misbehave with chunked responses. Other payloads are returned unchanged.
+    pub async fn buffered(self) -> Response {
+        let body = match self.payload {
+            HttpResponsePayload::Stream { body, length: None } => body,
+            payload => {
+                return Ok(HttpResponse {
+                    status: self.status,
+                    payload,
+                    headers: self.headers,
+                })
+            }
+        };
+
+        let chunks: Vec<Bytes> = body.try_collect().await?;
+        let body = Bytes::from(chunks.concat());
+        let length = Some(body.len() as u64);
+
+        Ok(HttpResponse {
+            status: self.status,
+            payload: HttpResponsePayload::Stream {
+                body: futures::stream::once(async move { Ok(body) }).boxed(),
+                length,
+            },
+            headers: self.headers,
+        })
+    }
+
+    /// Convert an actix response, eagerly buffering its body, so it can be shared between threads.
+    /// `Content-Length` is dropped as it is computed again when the response is sent.
+    pub async fn from_actix(resp: actix_web::HttpResponse) -> Response {
+        let status = resp.status();
+        let mut content_type = None;
+        let mut headers = vec![];
+        for (key, value) in resp.headers() {
+            let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+            if key == CONTENT_TYPE {
+                content_type = Some(value);
+            } else if key != CONTENT_LENGTH {
+                headers.push((key.to_string(), value));
+            }
+        }
+
+        let body = match actix_web::body::to_bytes(resp.into_body()).await {
+            Ok(body) => body,
+            Err(e) => return internal_server_error(e.to_string()),
+        };
+
+        let payload = if body.is_empty() && content_type.is_none() {
+            HttpResponsePayload::Empty
+        } else {
+            HttpResponsePayload::Bytes {
+                body,
+                content_type: content_type
+                    .unwrap_or_else(|| "application/octet-stream".to_string()),
+            }
+        };
+
+        Ok(HttpResponse {
+            status,
+            payload,
+            headers,
+        })
+    }
+
+    /// A 200 with the value wrapped under a `data` key: `{ "data": ... }`.
+    pub fn data_envelope<T>(value: T) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        HttpResponse::json_keyed("data", value)
+    }
+
+    /// A 200 with the value wrapped under the given key, e.g. `{ "user": ... }` for APIs
+    /// where the root key names the resource.
+    pub fn json_keyed<T>(key: &str, value: T) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        Ok(HttpResponse::Ok().json(serde_json::json!({ key: value })))
+    }
+
+    /// A 200 with the value under a `data` key and the meta under a `meta` key.
+    pub fn data_envelope_with_meta<T, M>(value: T, meta: M) -> Response
+    where
+        T: Serialize + 'static,
+        M: Serialize + 'static,
+    {
+        Ok(HttpResponse::Ok().json(serde_json::json!({ "data": value, "meta": meta })))
+    }
+
+    /// A 200 streaming the items as a single JSON array, without buffering them, failing fast
+    /// like `try_ndjson`: an error on the first item is returned as a regular error response.
+    /// An empty stream produces `[]`. Once the first item is sent the status can not change anymore,
+    /// so a later error ends the array with a last `{"error": ...}` element holding the error
+    /// response body, keeping the body valid JSON.
+    pub async fn stream_json_array<S, T>(stream: S) -> Response
+    where
+        S: Stream<Item = Result<T, HttpError>> + Send + 'static,
+        T: Serialize + Send + 'static,
+    {
+        let mut stream = stream.boxed();
+        let first = match stream.next().await {
+            Some(Ok(item)) => Some(Ok(item)),
+            Some(Err(e)) => return Err(e),
+            None => None,
+        };
+
+        let indexed = futures::stream::iter(first).chain(stream).enumerate();
+        let items = indexed.scan(false, |failed, (index, item)| {
+            if *failed {
+                return futures::future::ready(None);
+            }
+            let mut chunk = if index == 0 { vec![] } else { vec![b','] };
+            let value = item.and_then(|value| {
+                serde_json::to_vec(&value).map_err(|e| {
+                    HttpError::with_status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+                })
+            });
+            match value {
+                Ok(value) => chunk.extend(value),
+                Err(e) => {
+                    *failed = true;
+                    let frame = serde_json::json!({ "error": error_item_body(&e) });
+                    chunk.extend(frame.to_string().into_bytes());
+                }
+            }
+            futures::future::ready(Some(Ok(Bytes::from(chunk))))
+        });
+
+        let open = futures::stream::iter([Ok(Bytes::from_static(b"["))]);
+        let close = futures::stream::iter([Ok(Bytes::from_static(b"]"))]);
+        let body = open.chain(items).chain(close).boxed();
+
+        Ok(HttpResponse {
+            status: StatusCode::OK,
+            payload: HttpResponsePayload::Stream { body, length: None },
+            headers: vec![(CONTENT_TYPE.to_string(), ContentType::json().to_string())],
+        })
+    }
+
+    /// Serve a single byte range of the body as a 206 with `Content-Range`, according to the request `Range`.
+    /// Requests without a (single, valid) bytes range get the whole body as a 200.
+    /// An unsatisfiable range results in a 416. `Accept-Ranges: bytes` is always set.
+    /// The content type applies to both the whole body and the range, e.g. `video/mp4`.
+    pub fn range(
+        bytes: Bytes,
+        total_len: u64,
+        content_type: impl Into<String>,
+        req: &HttpRequest,
+    ) -> Response {
+        let accept_ranges = (ACCEPT_RANGES.to_string(), "bytes".to_string());
+        let content_type = content_type.into();
+
+        let spec = match Range::parse(req) {
+            Ok(Range::Bytes(specs)) if specs.len() == 1 => specs[0].clone(),
+            _ => {
+                return Ok(HttpResponse {
+                    status: StatusCode::OK,
+                    payload: HttpResponsePayload::Bytes {
+                        body: bytes,
+                        content_type,
+                    },
+                    headers: vec![accept_ranges],
+                })
+            }
+        };
+
+        let (start, end) = match spec.to_satisfiable_range(total_len) {
+            Some((start, end)) if end < bytes.len() as u64 => (start, end),
+            _ => {
+                return Err(HttpError::WithDetails(HttpErrorDetails {
+                    message: "Range not satisfiable".to_string(),
+                    status_code: StatusCode::RANGE_NOT_SATISFIABLE,
+                    headers: vec![
+                        accept_ranges,
+                        (CONTENT_RANGE.to_string(), format!("bytes */{}", total_len)),
+                    ],
+                }))
+            }
+        };
+
+        Ok(HttpResponse {
+            status: StatusCode::PARTIAL_CONTENT,
+            payload: HttpResponsePayload::Bytes {
+                body: bytes.slice(start as usize..=end as usize),
+                content_type,
+            },
+            headers: vec![
+                accept_ranges,
+                (
+                    CONTENT_RANGE.to_string(),
+                    format!("bytes {}-{}/{}", start, end, total_len),
+                ),
+            ],
+        })
+    }
+
+    /// A 200 with the bytes and a content type guessed from the path extension.
+    /// Guessing requires the `mime_guess` feature; `application/octet-stream` is used otherwise
+    /// and for unknown extensions.
+    pub fn bytes_for_path(bytes: impl Into<Bytes>, path: impl AsRef<Path>) -> Response {
+        #[cfg(feature = "mime_guess")]
+        let content_type = mime_guess::from_path(path)
+            .first_or_octet_stream()
+            .to_string();
+        #[cfg(not(feature = "mime_guess"))]
+        let content_type = {
+            let _ = path;
+            "application/octet-stream".to_string()
+        };
+
+        Ok(HttpResponse {
+            status: StatusCode::OK,
+            payload: HttpResponsePayload::Bytes {
+                body: bytes.into(),
+                content_type,
+            },
+            headers: vec![],
+        })
+    }
+
+    /// A 200 serving already gzipped bytes verbatim with `Content-Encoding: gzip`
+    /// and `Vary: Accept-Encoding`, e.g. for a cached document compressed once.
+    /// Only use it when the request `Accept-Encoding` allows gzip.
+    pub fn bytes_precompressed(gzipped: Vec<u8>, content_type: impl Into<String>) -> Response {
+        Ok(HttpResponse::Ok()
+            .add_header(CONTENT_ENCODING.to_string(), "gzip")
+            .vary(&["Accept-Encoding"])
+            .body_bytes(Bytes::from(gzipped), content_type))
+    }
+
+    /// A 201 with the `Location` of the created resource and an `ETag` computed from the body,
+    /// so the client can issue conditional requests right away.
+    pub fn created_with_etag<T>(location: impl Into<String>, body: T) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        let location = location.into();
+        if location.trim().is_empty() {
+            return bad_request("Location must not be empty");
+        }
+
+        let body = match serde_json::to_value(&body) {
+            Ok(body) => body,
+            Err(_) => {
+                panic!("Failed to serialize response body");
+            }
+        };
+        let etag = etag_for(&body, false);
+
+        Ok(HttpResponse::Created()
+            .add_header(LOCATION.to_string(), location)
+            .add_header(ETAG.to_string(), etag)
+            .json(body))
+    }
+
+    /// A 200 with the items as a JSON array and their number in the `X-Total-Count` header,
+    /// for list endpoints returning everything at once.
+    pub fn list_with_count<T>(items: Vec<T>) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        Ok(HttpResponse::Ok()
+            .add_header("X-Total-Count", items.len().to_string())
+            .json(items))
+    }
+
+    /// Collapse a `fetch_optional` result into a response: a 200 with the row, a 404 with the message
+    /// when there is none, or the mapped `DatabaseError` status on a database error.
+    pub fn from_optional_query<T>(
+        result: Result<Option<T>, sqlx::Error>,
+        not_found_message: impl Into<String>,
+    ) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        match result? {
+            Some(row) => Ok(HttpResponse::Ok().json(row)),
+            None => not_found(not_found_message),
+        }
+    }
+
+    /// A 304 when the `If-None-Match` header matches the current value of an optimistic locking
+    /// `version` column, or `None` to continue building the response.
+    /// The version is the opaque ETag value, e.g. `"3"`, so send it as the `ETag` of the full response.
+    pub fn not_modified_if_version(
+        req: &HttpRequest,
+        current_version: i64,
+    ) -> Option<HttpResponse> {
+        let matches = req
+            .headers()
+            .get_all(IF_NONE_MATCH)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim().trim_start_matches("W/").trim_matches('"'))
+            .any(|tag| tag == "*" || tag.parse::<i64>() == Ok(current_version));
+
+        if matches {
+            Some(
+                HttpResponseBuilder::new(StatusCode::NOT_MODIFIED)
+                    .add_header(ETAG.to_string(), format!("\"{}\"", current_version))
+                    .finish(),
+            )
+        } else {
+            None
+        }
+    }
+
+    /// A 200 with the value and its strong ETag, handling conditional GET and HEAD for cacheable reads:
+    /// a 304 when `If-None-Match` matches, and for HEAD requests a body-less 200 with the `ETag`
+    /// and the `Content-Length` the body would have.
+    pub fn conditional_json<T>(req: &HttpRequest, value: T) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        let response = HttpResponse::Ok().json_with_etag(req, value, false);
+        if req.method() != Method::HEAD {
+            return Ok(response);
+        }
+
+        let length = match &response.payload {
+            HttpResponsePayload::Json(body) => match serde_json::to_vec(body) {
+                Ok(body) => body.len() as u64,
+                Err(_) => {
+                    panic!("Failed to serialize response body");
+                }
+            },
+            _ => return Ok(response),
+        };
+        let mut headers = response.headers;
+        headers.push((CONTENT_TYPE.to_string(), "application/json".to_string()));
+
+        Ok(HttpResponse {
+            status: response.status,
+            payload: HttpResponsePayload::Stream {
+                body: futures::stream::empty().boxed(),
+                length: Some(length),
+            },
+            headers,
+        })
+    }
+
+    /// A 200 with a `CursorPage`: `{ "items": [...], "next_cursor": ... }`.
+    pub fn cursor_page<T>(items: Vec<T>, next_cursor: Option<String>) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        Ok(HttpResponse::Ok().json(CursorPage { items, next_cursor }))
+    }
+
+    /// Same as `cursor_page`, with a `rel="next"` `Link` to the base URL with the next cursor
+    /// in a `cursor` query param, e.g. `</users?cursor=abc>; rel="next"`. No link on the last page.
+    pub fn cursor_page_with_link<T>(
+        items: Vec<T>,
+        next_cursor: Option<String>,
+        base_url: &str,
+    ) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        let builder = match &next_cursor {
+            Some(cursor) => {
+                let separator = if base_url.contains('?') { '&' } else { '?' };
+                let cursor = utf8_percent_encode(cursor, NON_ALPHANUMERIC);
+                let url = format!("{}{}cursor={}", base_url, separator, cursor);
+                HttpResponse::Ok().link(&url, "next")
+            }
+            None => HttpResponse::Ok(),
+        };
+        Ok(builder.json(CursorPage { items, next_cursor }))
+    }
+
+    /// A 207 reporting the result of each item of a batch operation as an array of `SubResult`,
+    /// for bulk endpoints where some items may succeed while others fail.
+    pub fn multi_status(results: Vec<SubResult>) -> Response {
+        Ok(HttpResponseBuilder::new(StatusCode::MULTI_STATUS).json(results))
+    }
+
+    /// A 200 streaming the items as NDJSON, one JSON value per line, failing fast:
+    /// the first item is awaited before responding, so an error there is returned as a regular
+    /// error response. Once the first item is sent the status can not change anymore, so a later
+    /// error ends the stream with a last `{"error": ...}` line holding the error response body instead.
+    pub async fn try_ndjson<S, T>(stream: S) -> Response
+    where
+        S: Stream<Item = Result<T, HttpError>> + Send + 'static,
+        T: Serialize + Send + 'static,
+    {
+        let mut stream = stream.boxed();
+        let first = match stream.next().await {
+            Some(Ok(item)) => Some(Ok(item)),
+            Some(Err(e)) => return Err(e),
+            None => None,
+        };
+
+        let lines = futures::stream::iter(first)
+            .chain(stream)
+            .scan(false, |failed, item| {
+                if *failed {
+                    return futures::future::ready(None);
+                }
+                let line = item.and_then(|value| {
+                    serde_json::to_vec(&value).map_err(|e| {
+                        HttpError::with_status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+                    })
+                });
+                let mut line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        *failed = true;
+                        serde_json::json!({ "error": error_item_body(&e) })
+                            .to_string()
+                            .into_bytes()
+                    }
+                };
+                line.push(b'\n');
+                futures::future::ready(Some(Ok(Bytes::from(line))))
+            });
+
+        Ok(HttpResponse {
+            status: StatusCode::OK,
+            payload: HttpResponsePayload::Stream {
+                body: lines.boxed(),
+                length: None,
+            },
+            headers: vec![(CONTENT_TYPE.to_string(), "application/x-ndjson".to_string())],
+        })
+    }
+
+    /// A 200 with the bytes displayed inline by browsers, e.g. for PDF previews, with
+    /// `Content-Disposition: inline; filename="..."`. Non ASCII file names are sent RFC 5987 encoded
+    /// in `filename*`, along with an ASCII fallback in `filename`.
+    pub fn inline(
+        bytes: impl Into<Bytes>,
+        filename: &str,
+        content_type: impl Into<String>,
+    ) -> Response {
+        Ok(HttpResponse::Ok()
+            .add_header(
+                CONTENT_DISPOSITION.to_string(),
+                content_disposition(DispositionType::Inline, filename),
+            )
+            .body_bytes(bytes.into(), content_type))
+    }
+
+    /// A 204 when the resource exists, a 404 otherwise, for `HEAD` and existence check endpoints
+    /// backed by `SELECT EXISTS(...)`.
+    pub fn exists_or_not_found(exists: bool) -> Response {
+        if exists {
+            Ok(HttpResponse::NoContent().finish())
+        } else {
+            not_found("Not found")
+        }
+    }
+
+    /// A 200 with the resource as `application/hal+json`, with links keyed by relation,
+    /// e.g. `"self"` to `HalLink::new("/orders/1")`, and embedded resources keyed by name.
+    /// The resource must serialize to a JSON object, its fields are kept at the top level.
+    pub fn hal<T>(
+        resource: T,
+        links: BTreeMap<String, HalLink>,
+        embedded: BTreeMap<String, serde_json::Value>,
+    ) -> Response
+    where
+        T: Serialize,
+    {
+        let hal = Hal {
+            resource,
+            links,
+            embedded,
+        };
+        match serde_json::to_vec(&hal) {
+            Ok(body) => {
+                Ok(HttpResponse::Ok().body_bytes(Bytes::from(body), "application/hal+json"))
+            }
+            Err(_) => {
+                panic!("Failed to serialize response body");
+            }
+        }
+    }
+
+    /// A 200 with the value as JSON and a `Server-Timing: db;dur=...` metric from the measured
+    /// database time, see also `with_tx_timed` which measures it.
+    pub fn json_timed<T>(value: T, db_duration: Duration) -> Response
+    where
+        T: Serialize + 'static,
+    {
+        Ok(HttpResponse::Ok()
+            .server_timing("db", db_duration)
+            .json(value))
+    }
+
+    /// A 200 streaming successive pages as NDJSON, one `{ "items": ..., "next_cursor": ... }` line
+    /// per page, for bulk sync clients. The pages are fetched lazily over the given transaction,
+    /// starting with a `None` cursor and calling `page_fn` again with each `next_cursor`
+    /// until it is `None`; the transaction is then committed. Like `try_ndjson`, an error on the
+    /// first page is returned as a regular error response, while a later one ends the stream with
+    /// an `{"error": ...}` line. The transaction is rolled back on error, following
+    /// `set_propagate_rollback_errors`, and dropped, so rolled back by sqlx, when the client goes away.
+    pub async fn stream_pages<F, T, DB>(tx: Transaction<'static, DB>, page_fn: F) -> Response
+    where
+        F: for<'r> FnMut(
+                &'r mut Transaction<'static, DB>,
+                Option<String>,
+            )
+                -> ScopedBoxFuture<'static, 'r, Result<CursorPage<T>, HttpError>>
+            + Send
+            + 'static,
+        T: Serialize + Send + 'static,
+        DB: Database,
+    {
+        let pages = futures::stream::unfold(Some((tx, page_fn, None)), |state| async move {
+            let (mut tx, mut page_fn, cursor) = state?;
+            let page = match page_fn(&mut tx, cursor).await {
+                Ok(page) => page,
+                Err(e) => {
+                    let e = match crate::tx::rollback(tx).await {
+                        Ok(()) => e,
+                        Err(rollback_error) => HttpError::from(rollback_error),
+                    };
+                    return Some((Err(e), None));
+                }
+            };
+            match page.next_cursor.clone() {
+                Some(cursor) => Some((Ok(page), Some((tx, page_fn, Some(cursor))))),
+                None => match tx.commit().await {
+                    Ok(()) => Some((Ok(page), None)),
+                    Err(e) => Some((Err(HttpError::from(e)), None)),
+                },
+            }
+        });
+        HttpResponse::try_ndjson(pages).await
+    }
+}
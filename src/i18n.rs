@@ -0,0 +1,77 @@
+use crate::http::HttpError;
+use actix_web::http::header::{ACCEPT_LANGUAGE, CONTENT_LANGUAGE};
+use actix_web::HttpRequest;
+use std::sync::OnceLock;
+
+/// A catalog resolving message keys to localized messages.
+pub trait MessageCatalog: Send + Sync {
+    fn message(&self, key: &str, language: &str) -> Option<String>;
+}
+
+static CATALOG: OnceLock<Box<dyn MessageCatalog>> = OnceLock::new();
+
+/// Register the catalog used by `HttpError::localize`.
+/// It can only be set once, usually at startup; later calls are ignored.
+pub fn set_message_catalog(catalog: impl MessageCatalog + 'static) {
+    let _ = CATALOG.set(Box::new(catalog));
+}
+
+/// The languages of the `Accept-Language` header, ordered by preference.
+/// Region subtags are followed by their primary language, e.g. `pt-BR` then `pt`.
+pub fn accepted_languages(req: &HttpRequest) -> Vec<String> {
+    let mut languages: Vec<(String, f32)> = req
+        .headers()
+        .get_all(ACCEPT_LANGUAGE)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if tag.is_empty() || tag == "*" || quality <= 0.0 {
+                None
+            } else {
+                Some((tag.to_string(), quality))
+            }
+        })
+        .collect();
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut result: Vec<String> = vec![];
+    for (tag, _) in languages {
+        let primary = tag.split('-').next().unwrap_or(&tag).to_string();
+        for language in [tag, primary] {
+            if !result.iter().any(|l| l.eq_ignore_ascii_case(&language)) {
+                result.push(language);
+            }
+        }
+    }
+    result
+}
+
+impl HttpError {
+    /// Resolve the `WithDetails` message against the registered catalog using the request `Accept-Language`.
+    /// The message is kept as is when no catalog is registered or no entry exists for the accepted languages,
+    /// otherwise the language it was resolved in is declared with `Content-Language`.
+    /// `ResponseError::error_response` has no access to the request, so call this before returning the error.
+    pub fn localize(self, req: &HttpRequest) -> HttpError {
+        let (catalog, mut details) = match (CATALOG.get(), self) {
+            (Some(catalog), HttpError::WithDetails(details)) => (catalog, details),
+            (_, error) => return error,
+        };
+
+        if let Some((message, language)) = accepted_languages(req)
+            .into_iter()
+            .find_map(|language| Some((catalog.message(&details.message, &language)?, language)))
+        {
+            details.message = message;
+            details
+                .headers
+                .push((CONTENT_LANGUAGE.to_string(), language));
+        }
+        HttpError::WithDetails(details)
+    }
+}
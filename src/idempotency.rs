@@ -0,0 +1,209 @@
+use crate::http::{conflict, HttpError, HttpResponse, HttpResponsePayload, Response};
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// A response as kept by an `IdempotencyStore`, so it can be replayed on retries.
+/// The body is kept as the bytes sent, along with their content type, so any buffered payload
+/// replays exactly; `None` for empty responses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+    pub content_type: Option<String>,
+}
+
+impl StoredResponse {
+    /// The response to store, or `None` for a streamed response, which can not be replayed.
+    pub fn from_response(response: &HttpResponse) -> Option<StoredResponse> {
+        let (body, content_type) = match &response.payload {
+            HttpResponsePayload::Json(value) => match serde_json::to_vec(value) {
+                Ok(body) => (Some(body), Some("application/json".to_string())),
+                Err(_) => {
+                    panic!("Failed to serialize response body");
+                }
+            },
+            HttpResponsePayload::Bytes { body, content_type } => {
+                (Some(body.to_vec()), Some(content_type.clone()))
+            }
+            HttpResponsePayload::Stream { .. } => return None,
+            HttpResponsePayload::Empty => (None, None),
+        };
+
+        Some(StoredResponse {
+            status: response.status.as_u16(),
+            headers: response.headers.clone(),
+            body,
+            content_type,
+        })
+    }
+}
+
+impl From<StoredResponse> for HttpResponse {
+    fn from(stored: StoredResponse) -> Self {
+        HttpResponse {
+            status: StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK),
+            payload: match stored.body {
+                Some(body) => HttpResponsePayload::Bytes {
+                    body: Bytes::from(body),
+                    content_type: stored
+                        .content_type
+                        .unwrap_or_else(|| "application/octet-stream".to_string()),
+                },
+                None => HttpResponsePayload::Empty,
+            },
+            headers: stored.headers,
+        }
+    }
+}
+
+/// Storage for responses keyed by the request `Idempotency-Key` header.
+/// `idempotent` clones the store to release a key from a spawned task when the handler
+/// is cancelled, so wrap it in an `Arc` or make it a cheap handle, e.g. around a pool.
+pub trait IdempotencyStore {
+    fn get(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<StoredResponse>, HttpError>> + Send;
+
+    /// Mark the key as in flight before the handler runs, returning `false` when it already
+    /// is, or already has a stored response. This must be atomic, e.g. an `INSERT ... ON CONFLICT
+    /// DO NOTHING` or a Redis `SET NX`, so concurrent retries can not both run the handler.
+    /// In flight marks must expire after a TTL, e.g. a Redis `SET NX EX`, since the key
+    /// is not released when the process dies mid request or when storing the response fails.
+    fn reserve(&self, key: &str) -> impl Future<Output = Result<bool, HttpError>> + Send;
+
+    /// Store the response of a reserved key, replacing its in flight mark.
+    fn put(
+        &self,
+        key: &str,
+        response: StoredResponse,
+    ) -> impl Future<Output = Result<(), HttpError>> + Send;
+
+    /// Drop the in flight mark of a reserved key whose handler failed, so it can be retried.
+    fn release(&self, key: &str) -> impl Future<Output = Result<(), HttpError>> + Send;
+}
+
+impl<S: IdempotencyStore + Send + Sync> IdempotencyStore for Arc<S> {
+    async fn get(&self, key: &str) -> Result<Option<StoredResponse>, HttpError> {
+        (**self).get(key).await
+    }
+
+    async fn reserve(&self, key: &str) -> Result<bool, HttpError> {
+        (**self).reserve(key).await
+    }
+
+    async fn put(&self, key: &str, response: StoredResponse) -> Result<(), HttpError> {
+        (**self).put(key, response).await
+    }
+
+    async fn release(&self, key: &str) -> Result<(), HttpError> {
+        (**self).release(key).await
+    }
+}
+
+/// Releases a reserved key from a spawned task when dropped while still armed,
+/// e.g. when the handler future is dropped because the client went away.
+struct Reservation<S: IdempotencyStore + Clone + Send + Sync + 'static> {
+    store: S,
+    key: Option<String>,
+}
+
+impl<S: IdempotencyStore + Clone + Send + Sync + 'static> Reservation<S> {
+    /// Keep the in flight mark, once the response is about to be stored.
+    fn keep(&mut self) {
+        self.key = None;
+    }
+
+    async fn release(mut self) -> Result<(), HttpError> {
+        let res = match &self.key {
+            Some(key) => self.store.release(key).await,
+            None => Ok(()),
+        };
+        self.key = None;
+        res
+    }
+}
+
+impl<S: IdempotencyStore + Clone + Send + Sync + 'static> Drop for Reservation<S> {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let store = self.store.clone();
+        runtime.spawn(async move {
+            if let Err(e) = store.release(&key).await {
+                tracing::warn!(error = %e, "failed to release the Idempotency-Key of a cancelled request");
+            }
+        });
+    }
+}
+
+/// Replay the stored response for the request `Idempotency-Key`, or run the handler and store its response.
+/// Requests without the header always run the handler.
+/// The key is reserved before the handler runs, and a request whose key is still in flight
+/// gets a 409, so concurrent retries never run the handler twice.
+/// Only successful responses are stored, so a failed request can be retried with the same key.
+/// The key is also released when the handler is cancelled, e.g. by a client disconnect.
+/// Streamed responses can not be replayed, they are never stored and their key is released.
+/// If storing the response fails, the error is logged and the response is still returned;
+/// the key keeps its in flight mark until its TTL, so retries get a 409 rather than
+/// running the handler, e.g. charging a payment, a second time.
+pub async fn idempotent<S, F, Fut>(store: &S, req: &HttpRequest, handler: F) -> Response
+where
+    S: IdempotencyStore + Clone + Send + Sync + 'static,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let key = match req
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(key) => key.to_string(),
+        None => return handler().await,
+    };
+
+    if let Some(stored) = store.get(&key).await? {
+        return Ok(stored.into());
+    }
+
+    if !store.reserve(&key).await? {
+        // The key may have completed between `get` and `reserve`.
+        if let Some(stored) = store.get(&key).await? {
+            return Ok(stored.into());
+        }
+        return conflict("A request with the same Idempotency-Key is in progress");
+    }
+
+    let mut reservation = Reservation {
+        store: store.clone(),
+        key: Some(key.clone()),
+    };
+    let response = match handler().await {
+        Ok(response) => response,
+        Err(e) => {
+            reservation.release().await?;
+            return Err(e);
+        }
+    };
+    match StoredResponse::from_response(&response) {
+        Some(stored) => {
+            reservation.keep();
+            if let Err(e) = store.put(&key, stored).await {
+                tracing::error!(error = %e, key, "failed to store the idempotent response");
+            }
+        }
+        None => reservation.release().await?,
+    }
+    Ok(response)
+}
@@ -1,2 +1,7 @@
+pub mod audit;
+pub mod extract;
 pub mod http;
+pub mod i18n;
+pub mod idempotency;
+pub mod middleware;
 pub mod tx;
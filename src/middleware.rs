@@ -0,0 +1,189 @@
+use crate::http::HttpError;
+use crate::tx::rollback;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use sqlx::{Database, Pool, Transaction};
+use std::rc::Rc;
+use std::sync::Arc;
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+/// What to do with the request transaction once the response is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxOutcome {
+    Commit,
+    Rollback,
+}
+
+/// The default policy: commit on 2xx/3xx and roll back otherwise.
+pub fn commit_on_success(status: StatusCode) -> TxOutcome {
+    if status.is_success() || status.is_redirection() {
+        TxOutcome::Commit
+    } else {
+        TxOutcome::Rollback
+    }
+}
+
+struct TxSlot<DB: Database> {
+    tx: Option<Transaction<'static, DB>>,
+    force_commit: bool,
+}
+
+struct TxContext<DB: Database> {
+    pool: Pool<DB>,
+    slot: Arc<Mutex<TxSlot<DB>>>,
+}
+
+impl<DB: Database> Clone for TxContext<DB> {
+    fn clone(&self) -> Self {
+        TxContext {
+            pool: self.pool.clone(),
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+/// Middleware providing a per-request transaction to handlers through the `Tx` extractor.
+/// The transaction only begins when a handler extracts `Tx`, and once the response is ready
+/// it is committed or rolled back according to the policy applied to the response status.
+pub struct TxMiddleware<DB: Database> {
+    pool: Pool<DB>,
+    policy: fn(StatusCode) -> TxOutcome,
+}
+
+impl<DB: Database> TxMiddleware<DB> {
+    pub fn new(pool: Pool<DB>) -> Self {
+        TxMiddleware {
+            pool,
+            policy: commit_on_success,
+        }
+    }
+
+    /// Replace the default `commit_on_success` policy,
+    /// e.g. to keep the side effects of some 4xx responses.
+    pub fn policy(mut self, policy: fn(StatusCode) -> TxOutcome) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl<S, B, DB> Transform<S, ServiceRequest> for TxMiddleware<DB>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+    DB: Database,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = TxMiddlewareService<S, DB>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TxMiddlewareService {
+            service: Rc::new(service),
+            pool: self.pool.clone(),
+            policy: self.policy,
+        }))
+    }
+}
+
+pub struct TxMiddlewareService<S, DB: Database> {
+    service: Rc<S>,
+    pool: Pool<DB>,
+    policy: fn(StatusCode) -> TxOutcome,
+}
+
+impl<S, B, DB> Service<ServiceRequest> for TxMiddlewareService<S, DB>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+    DB: Database,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let context = TxContext {
+            pool: self.pool.clone(),
+            slot: Arc::new(Mutex::new(TxSlot {
+                tx: None,
+                force_commit: false,
+            })),
+        };
+        req.extensions_mut().insert(context.clone());
+
+        let policy = self.policy;
+        let service = self.service.clone();
+        Box::pin(async move {
+            let res = service.call(req).await;
+
+            let mut slot = context.slot.lock().await;
+            if let Some(tx) = slot.tx.take() {
+                let outcome = match &res {
+                    _ if slot.force_commit => TxOutcome::Commit,
+                    Ok(res) => policy(res.status()),
+                    Err(e) => policy(e.as_response_error().status_code()),
+                };
+                match outcome {
+                    TxOutcome::Commit => tx.commit().await.map_err(HttpError::from)?,
+                    TxOutcome::Rollback => rollback(tx).await.map_err(HttpError::from)?,
+                }
+            }
+
+            res
+        })
+    }
+}
+
+/// The per-request transaction provided by `TxMiddleware`.
+pub struct Tx<DB: Database> {
+    slot: Arc<Mutex<TxSlot<DB>>>,
+}
+
+impl<DB: Database> Tx<DB> {
+    /// Lock the transaction to run queries on it.
+    pub async fn lock(&self) -> MappedMutexGuard<'_, Transaction<'static, DB>> {
+        MutexGuard::map(self.slot.lock().await, |slot| {
+            slot.tx.as_mut().expect("Transaction already completed")
+        })
+    }
+
+    /// Commit the transaction whatever the response status is.
+    pub async fn force_commit(&self) {
+        self.slot.lock().await.force_commit = true;
+    }
+}
+
+impl<DB: Database> FromRequest for Tx<DB> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let context = req.extensions().get::<TxContext<DB>>().cloned();
+        Box::pin(async move {
+            let context = match context {
+                Some(context) => context,
+                None => {
+                    return Err(HttpError::with_status(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "TxMiddleware is not registered",
+                    )
+                    .into())
+                }
+            };
+
+            let mut slot = context.slot.lock().await;
+            if slot.tx.is_none() {
+                slot.tx = Some(context.pool.begin().await.map_err(HttpError::from)?);
+            }
+            drop(slot);
+
+            Ok(Tx { slot: context.slot })
+        })
+    }
+}
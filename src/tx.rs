@@ -1,25 +1,1018 @@
+#[cfg(feature = "postgres")]
+use crate::http::bad_request;
+use crate::http::{
+    conflict, gateway_timeout, internal_server_error, push_server_timing, sqlstate, HttpError,
+    HttpResponse, Paginated,
+};
+#[cfg(feature = "postgres")]
+use actix_web::http::Method;
+use actix_web::web;
+use futures::channel::mpsc;
+use futures::stream::BoxStream;
+use futures::{SinkExt, StreamExt};
 use scoped_futures::ScopedBoxFuture;
+#[cfg(feature = "postgres")]
+use scoped_futures::ScopedFutureExt;
+#[cfg(feature = "postgres")]
+use sqlx::Executor;
 use sqlx::{Database, Pool, Transaction};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::Instrument;
 
 /// Run a callback with a transaction.
 /// If the callback returns an error, the transaction is rolled back.
 /// If the callback returns Ok, the transaction is committed.
+/// The callback error is returned as is after the rollback, so returning e.g. `conflict(...)`
+/// from the callback both undoes its writes and responds with that status.
 pub async fn with_tx<'a, F, R, E, DB>(pool: &Pool<DB>, callback: F) -> Result<R, E>
 where
     F: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
     E: From<sqlx::Error> + Send + 'a,
     R: Send + 'a,
     DB: Database,
+{
+    let (response, tx) = begin_with(pool, callback).await?;
+    tx.commit().await?;
+    Ok(response)
+}
+
+/// Begin a transaction and run a callback with it, handing back the still open transaction
+/// along with the callback value, to compose transaction phases across function boundaries.
+/// If the callback returns an error, the transaction is rolled back.
+/// If the callback returns Ok, the caller is responsible for committing or rolling back the transaction;
+/// dropping it rolls it back.
+pub async fn begin_with<'a, F, R, E, DB>(
+    pool: &Pool<DB>,
+    callback: F,
+) -> Result<(R, Transaction<'static, DB>), E>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    let mut tx = pool.begin().await?;
+    match callback(&mut tx).await {
+        Ok(response) => Ok((response, tx)),
+        Err(e) => {
+            rollback(tx).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Same as `with_tx`, but logs the callback error right before rolling back,
+/// so rollbacks are not silent while developing.
+pub async fn with_tx_debug<'a, F, R, E, DB>(pool: &Pool<DB>, callback: F) -> Result<R, E>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+    E: From<sqlx::Error> + Display + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    let mut tx = pool.begin().await?;
+    let res = callback(&mut tx).await;
+    if let Err(e) = &res {
+        tracing::warn!(error = %e, "rolling back transaction");
+    }
+    commit_or_rollback(tx, res).await
+}
+
+/// Same as `with_tx`, but any error, from the database or the callback itself, is logged
+/// and replaced by a 500 with a generic message, for public endpoints that must not leak anything.
+pub async fn with_tx_opaque<'a, F, R, E, DB>(pool: &Pool<DB>, callback: F) -> Result<R, HttpError>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+    E: From<sqlx::Error> + Display + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    match with_tx(pool, callback).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            tracing::error!("Transaction failed: {}", e);
+            internal_server_error("Internal server error")
+        }
+    }
+}
+
+/// Same as `with_tx`, but runs inside a `tx` tracing span with the given operation name
+/// and emits a `transaction finished` event with the `name`, `outcome` (commit or rollback)
+/// and `elapsed_ms` fields, for per-operation durations and commit/rollback rates.
+pub async fn with_tx_named<'a, F, R, E, DB>(
+    pool: &Pool<DB>,
+    name: &'static str,
+    callback: F,
+) -> Result<R, E>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    let span = tracing::info_span!("tx", name);
+    async move {
+        let started = Instant::now();
+        let mut tx = pool.begin().await?;
+        let res = callback(&mut tx).await;
+        let outcome = if res.is_ok() { "commit" } else { "rollback" };
+        let res = commit_or_rollback(tx, res).await;
+        tracing::info!(
+            name,
+            outcome,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "transaction finished"
+        );
+        res
+    }
+    .instrument(span)
+    .await
+}
+
+/// Same as `with_tx_named`, for callbacks returning a response: the time from begin to commit
+/// is added to it as a `Server-Timing: db;dur=...` metric, for database timings in the browser.
+pub async fn with_tx_timed<'a, F, E, DB>(
+    pool: &Pool<DB>,
+    name: &'static str,
+    callback: F,
+) -> Result<HttpResponse, E>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<HttpResponse, E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    DB: Database,
+{
+    let started = Instant::now();
+    let mut response = with_tx_named(pool, name, callback).await?;
+    push_server_timing(&mut response.headers, "db", started.elapsed());
+    Ok(response)
+}
+
+/// Run a callback with a transaction that must complete before the given deadline,
+/// e.g. derived from a gateway deadline header so chained operations share one budget.
+/// The deadline also bounds acquiring the connection, so a saturated pool results in a 504 too.
+/// If the deadline passes first, the callback is cancelled, the transaction is rolled back
+/// and a 504 is returned. Otherwise this behaves like `with_tx`.
+pub async fn with_tx_deadline<'a, F, R, E, DB>(
+    pool: &Pool<DB>,
+    deadline: Instant,
+    callback: F,
+) -> Result<R, E>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+    E: From<sqlx::Error> + From<HttpError> + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    let deadline = tokio::time::Instant::from_std(deadline);
+    let mut tx = match tokio::time::timeout_at(deadline, pool.begin()).await {
+        Ok(tx) => tx?,
+        Err(_) => return gateway_timeout("Transaction deadline exceeded").map_err(E::from),
+    };
+    match tokio::time::timeout_at(deadline, callback(&mut tx)).await {
+        Ok(res) => commit_or_rollback(tx, res).await,
+        Err(_) => {
+            rollback(tx).await?;
+            gateway_timeout("Transaction deadline exceeded").map_err(E::from)
+        }
+    }
+}
+
+/// Run a setup callback and then the main callback within the same transaction.
+/// The setup callback runs right after the transaction begins, e.g. to set the current tenant for RLS policies.
+/// If either callback returns an error, the transaction is rolled back.
+/// If both callbacks return Ok, the transaction is committed.
+pub async fn with_tx_setup<'a, S, F, R, E, DB>(
+    pool: &Pool<DB>,
+    setup: S,
+    callback: F,
+) -> Result<R, E>
+where
+    S: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<(), E>>
+        + Send
+        + 'a,
+    F: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
 {
     let mut tx = pool.begin().await?;
+    if let Err(e) = setup(&mut tx).await {
+        rollback(tx).await?;
+        return Err(e);
+    }
     let res = callback(&mut tx).await;
+    commit_or_rollback(tx, res).await
+}
+
+/// Run a callback with a read-only transaction, using the Postgres `SET TRANSACTION READ ONLY`.
+/// If the callback returns an error, the transaction is rolled back.
+/// If the callback returns Ok, the transaction is committed.
+#[cfg(feature = "postgres")]
+pub async fn with_read_tx<'a, F, R, E>(pool: &Pool<sqlx::Postgres>, callback: F) -> Result<R, E>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<sqlx::Postgres>) -> ScopedBoxFuture<'a, 'r, Result<R, E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    R: Send + 'a,
+{
+    with_tx_setup(
+        pool,
+        |tx| {
+            async move {
+                (&mut **tx).execute("SET TRANSACTION READ ONLY").await?;
+                Ok(())
+            }
+            .scope_boxed()
+        },
+        callback,
+    )
+    .await
+}
+
+/// Per-transaction Postgres settings applied by `with_tx_options` with `SET LOCAL` right after begin.
+#[cfg(feature = "postgres")]
+#[derive(Debug, Clone, Default)]
+pub struct TxOptions {
+    /// Comma separated schema names, e.g. `tenant_1, public`.
+    pub search_path: Option<String>,
+    pub role: Option<String>,
+    pub statement_timeout: Option<Duration>,
+}
+
+#[cfg(feature = "postgres")]
+impl TxOptions {
+    /// The `SET LOCAL` statements for the options, or a 400 naming the first option whose value is not
+    /// a plain identifier, as the values are interpolated into the statements.
+    fn statements(&self) -> Result<Vec<String>, HttpError> {
+        let mut statements = vec![];
+        if let Some(search_path) = &self.search_path {
+            let schemas: Vec<&str> = search_path.split(',').map(str::trim).collect();
+            if !schemas.iter().all(|schema| is_identifier(schema)) {
+                return bad_request("Invalid search_path");
+            }
+            statements.push(format!("SET LOCAL search_path TO {}", schemas.join(", ")));
+        }
+        if let Some(role) = &self.role {
+            if !is_identifier(role) {
+                return bad_request("Invalid role");
+            }
+            statements.push(format!("SET LOCAL ROLE {}", role));
+        }
+        if let Some(statement_timeout) = self.statement_timeout {
+            statements.push(format!(
+                "SET LOCAL statement_timeout = {}",
+                statement_timeout.as_millis()
+            ));
+        }
+        Ok(statements)
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn is_identifier(value: &str) -> bool {
+    let mut chars = value.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        }
+        _ => false,
+    }
+}
+
+/// Run a callback with a transaction using the given `TxOptions`, e.g. a tenant `search_path`.
+/// The options only last for the transaction. Invalid option values are rejected with a 400
+/// before the transaction begins.
+/// If the callback returns an error, the transaction is rolled back.
+/// If the callback returns Ok, the transaction is committed.
+#[cfg(feature = "postgres")]
+pub async fn with_tx_options<'a, F, R, E>(
+    pool: &Pool<sqlx::Postgres>,
+    options: &TxOptions,
+    callback: F,
+) -> Result<R, E>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<sqlx::Postgres>) -> ScopedBoxFuture<'a, 'r, Result<R, E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + From<HttpError> + Send + 'a,
+    R: Send + 'a,
+{
+    let statements = options.statements()?;
+    with_tx_setup(
+        pool,
+        |tx| {
+            async move {
+                for statement in statements {
+                    (&mut **tx).execute(statement.as_str()).await?;
+                }
+                Ok(())
+            }
+            .scope_boxed()
+        },
+        callback,
+    )
+    .await
+}
+
+/// Run a callback with a read-only transaction for safe methods (GET, HEAD, OPTIONS)
+/// and with a read-write transaction for any other method.
+#[cfg(feature = "postgres")]
+pub async fn with_tx_for_method<'a, F, R, E>(
+    pool: &Pool<sqlx::Postgres>,
+    method: &Method,
+    callback: F,
+) -> Result<R, E>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<sqlx::Postgres>) -> ScopedBoxFuture<'a, 'r, Result<R, E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    R: Send + 'a,
+{
+    if method == Method::GET || method == Method::HEAD || method == Method::OPTIONS {
+        with_read_tx(pool, callback).await
+    } else {
+        with_tx(pool, callback).await
+    }
+}
+
+/// Run a callback with a transaction, mapping the callback error once the transaction is rolled back.
+/// The `map_err` function runs after the rollback, so enriching the error can never skip it.
+/// If the callback returns Ok, the transaction is committed.
+pub async fn with_tx_map_err<'a, F, M, R, E, E2, DB>(
+    pool: &Pool<DB>,
+    callback: F,
+    map_err: M,
+) -> Result<R, E2>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+    M: FnOnce(E) -> E2,
+    E: Send + 'a,
+    E2: From<sqlx::Error>,
+    R: Send + 'a,
+    DB: Database,
+{
+    let mut tx = pool.begin().await?;
+    match callback(&mut tx).await {
+        Ok(response) => {
+            tx.commit().await?;
+            Ok(response)
+        }
+        Err(e) => {
+            rollback(tx).await?;
+            Err(map_err(e))
+        }
+    }
+}
+
+/// Run CPU-bound work, e.g. password hashing, on actix's blocking thread pool via `web::block`
+/// so it does not starve the async executor. Usable inside any transaction callback,
+/// e.g. to process rows read earlier in the transaction. A panic of the work is a 500.
+pub async fn blocking<T, B>(blocking_fn: B) -> Result<T, HttpError>
+where
+    B: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match web::block(blocking_fn).await {
+        Ok(value) => Ok(value),
+        Err(e) => internal_server_error(e.to_string()),
+    }
+}
+
+/// Run CPU-bound work on the blocking thread pool, then a callback with a transaction and the
+/// work output, e.g. to hash a password and then store it.
+/// The transaction only begins once the work is done, so no pooled connection is held during it.
+/// If the work panics, no transaction is begun and a 500 is returned.
+/// For work that needs data read in the transaction, call `blocking` inside a `with_tx` callback.
+/// If the callback returns an error, the transaction is rolled back.
+/// If the callback returns Ok, the transaction is committed.
+pub async fn with_tx_blocking<'a, B, T, F, R, E, DB>(
+    pool: &Pool<DB>,
+    blocking_fn: B,
+    callback: F,
+) -> Result<R, E>
+where
+    B: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+    F: for<'r> FnOnce(&'r mut Transaction<DB>, T) -> ScopedBoxFuture<'a, 'r, Result<R, E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + From<HttpError> + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    let output = blocking(blocking_fn).await?;
+    let mut tx = pool.begin().await?;
+    let res = callback(&mut tx, output).await;
+    commit_or_rollback(tx, res).await
+}
+
+/// An error that may succeed when the transaction is retried.
+pub trait RetryableError {
+    fn is_retryable(&self) -> bool;
+}
+
+/// Serialization failures and deadlocks, SQLSTATE `40001` and `40P01`, are retryable.
+impl RetryableError for sqlx::Error {
+    fn is_retryable(&self) -> bool {
+        matches!(sqlstate(self).as_deref(), Some("40001") | Some("40P01"))
+    }
+}
+
+impl RetryableError for HttpError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            HttpError::DatabaseError(er) => er.is_retryable(),
+            _ => false,
+        }
+    }
+}
+
+/// Run a callback with a transaction, retrying it while it fails with a retryable error
+/// such as a serialization failure, within a total time budget across all attempts.
+/// Retries wait for a jittered exponential backoff. No new attempt is started once the
+/// elapsed time plus the backoff and the duration of the last attempt would exceed the budget;
+/// the last error is then returned as is.
+/// Each attempt, including beginning its transaction, is cut off when the budget runs out;
+/// the transaction is then rolled back and a 504 is returned.
+/// The callback runs once per attempt, each in a new transaction.
+pub async fn with_tx_retry_budget<'a, F, R, E, DB>(
+    pool: &Pool<DB>,
+    total_budget: Duration,
+    callback: F,
+) -> Result<R, E>
+where
+    F: for<'r> Fn(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+    E: From<sqlx::Error> + From<HttpError> + RetryableError + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    let started = Instant::now();
+    let deadline = tokio::time::Instant::from_std(started + total_budget);
+    let mut attempt = 0;
+    loop {
+        let attempt_started = Instant::now();
+        let mut tx = match tokio::time::timeout_at(deadline, pool.begin()).await {
+            Ok(tx) => tx?,
+            Err(_) => return gateway_timeout("Transaction retry budget exceeded").map_err(E::from),
+        };
+        let res = match tokio::time::timeout_at(deadline, callback(&mut tx)).await {
+            Ok(res) => res,
+            Err(_) => {
+                rollback(tx).await?;
+                return gateway_timeout("Transaction retry budget exceeded").map_err(E::from);
+            }
+        };
+        let e = match commit_or_rollback(tx, res).await {
+            Ok(response) => return Ok(response),
+            Err(e) => e,
+        };
+
+        attempt += 1;
+        let last_attempt = attempt_started.elapsed();
+        let backoff = retry_backoff(attempt);
+        if !e.is_retryable() || started.elapsed() + backoff + last_attempt > total_budget {
+            return Err(e);
+        }
+        tracing::debug!(
+            attempt,
+            ?backoff,
+            "retrying transaction after a retryable error"
+        );
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// A random duration up to 10ms doubled per attempt, at most 1s ("full jitter"),
+/// so concurrent transactions that conflicted do not retry in lockstep.
+fn retry_backoff(attempt: u32) -> Duration {
+    let cap = Duration::from_millis(10)
+        .saturating_mul(1 << attempt.min(7))
+        .min(Duration::from_secs(1));
+    // Each `RandomState` is seeded differently, which is enough randomness for jitter.
+    let random = RandomState::new().build_hasher().finish();
+    cap.mul_f64((random % 1000) as f64 / 1000.0)
+}
+
+/// Run a page query and a count query within the same transaction and build a `Paginated` result.
+/// Running both in one transaction keeps the total consistent with the page under concurrent writes.
+/// If either query returns an error, the transaction is rolled back.
+pub async fn with_tx_paginated<'a, P, C, T, E, DB>(
+    pool: &Pool<DB>,
+    page_query: P,
+    count_query: C,
+) -> Result<Paginated<T>, E>
+where
+    P: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<Vec<T>, E>>
+        + Send
+        + 'a,
+    C: for<'r> FnOnce(&'r mut Transaction<DB>) -> ScopedBoxFuture<'a, 'r, Result<i64, E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    T: Send + 'a,
+    DB: Database,
+{
+    let mut tx = pool.begin().await?;
+    let items = match page_query(&mut tx).await {
+        Ok(items) => items,
+        Err(e) => {
+            rollback(tx).await?;
+            return Err(e);
+        }
+    };
+    let res = count_query(&mut tx)
+        .await
+        .map(|total| Paginated { items, total });
+    commit_or_rollback(tx, res).await
+}
+
+/// The error returned by `with_tx_batch`, telling which chunk failed.
+/// Chunks before `chunk` were already committed and are not rolled back.
+#[derive(Debug)]
+pub struct BatchError<E> {
+    pub chunk: usize,
+    pub processed: usize,
+    pub error: E,
+}
+
+impl<E: Display> Display for BatchError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "chunk {} failed after {} items were committed: {}",
+            self.chunk, self.processed, self.error
+        )
+    }
+}
+
+/// Run a callback for every chunk of `chunk_size` items, each one in its own transaction.
+/// Each chunk is committed before the next one begins, bounding the transaction size and lock duration.
+/// This is not atomic across chunks: when a chunk fails, only that chunk is rolled back.
+/// Returns the total number of items processed, or the failed chunk as a `BatchError`.
+pub async fn with_tx_batch<'a, T, F, E, DB>(
+    pool: &Pool<DB>,
+    items: Vec<T>,
+    chunk_size: usize,
+    mut callback: F,
+) -> Result<usize, BatchError<E>>
+where
+    F: for<'r> FnMut(&'r mut Transaction<DB>, Vec<T>) -> ScopedBoxFuture<'a, 'r, Result<(), E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    T: Send + 'a,
+    DB: Database,
+{
+    let chunk_size = chunk_size.max(1);
+    let mut items = items.into_iter().peekable();
+    let mut processed = 0;
+    let mut chunk = 0;
+
+    while items.peek().is_some() {
+        let batch: Vec<T> = items.by_ref().take(chunk_size).collect();
+        let len = batch.len();
+
+        let res = async {
+            let mut tx = pool.begin().await?;
+            let res = callback(&mut tx, batch).await;
+            commit_or_rollback(tx, res).await
+        }
+        .await;
+
+        if let Err(error) = res {
+            return Err(BatchError {
+                chunk,
+                processed,
+                error,
+            });
+        }
+
+        processed += len;
+        chunk += 1;
+    }
+
+    Ok(processed)
+}
+
+/// Run a streaming query with a transaction that stays open while the returned stream is consumed.
+/// The query runs on a spawned task owning the transaction, and its items are forwarded to the returned stream.
+/// The transaction is committed once the query stream completes, and rolled back if it yields an error
+/// or if the returned stream is dropped before completion.
+pub fn with_tx_stream<F, T, E, DB>(pool: &Pool<DB>, query_fn: F) -> BoxStream<'static, Result<T, E>>
+where
+    F: for<'r> FnOnce(&'r mut Transaction<DB>) -> BoxStream<'r, Result<T, E>> + Send + 'static,
+    E: From<sqlx::Error> + Send + 'static,
+    T: Send + 'static,
+    DB: Database,
+{
+    let pool = pool.clone();
+    let (mut sender, receiver) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        let mut tx = match pool.begin().await {
+            Ok(tx) => tx,
+            Err(e) => {
+                let _ = sender.send(Err(E::from(e))).await;
+                return;
+            }
+        };
+
+        let completed = {
+            let mut stream = query_fn(&mut tx);
+            loop {
+                match stream.next().await {
+                    Some(Ok(item)) => {
+                        if sender.send(Ok(item)).await.is_err() {
+                            break false;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        let _ = sender.send(Err(e)).await;
+                        break false;
+                    }
+                    None => break true,
+                }
+            }
+        };
+
+        let res = if completed {
+            tx.commit().await
+        } else {
+            rollback(tx).await
+        };
+        if let Err(e) = res {
+            let _ = sender.send(Err(E::from(e))).await;
+        }
+    });
+
+    receiver.boxed()
+}
+
+/// Something that can run a callback inside a transaction.
+/// Implemented for `Pool`, so service code generic over `TxRunner` can be unit tested
+/// with a fake runner that records commits and rollbacks instead of hitting a database.
+pub trait TxRunner {
+    type Tx: Send;
+
+    fn run<'a, F, R, E>(&'a self, callback: F) -> impl Future<Output = Result<R, E>> + Send + 'a
+    where
+        F: for<'r> FnOnce(&'r mut Self::Tx) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+        E: From<sqlx::Error> + Send + 'a,
+        R: Send + 'a;
+}
+
+impl<DB: Database> TxRunner for Pool<DB> {
+    type Tx = Transaction<'static, DB>;
+
+    async fn run<'a, F, R, E>(&'a self, callback: F) -> Result<R, E>
+    where
+        F: for<'r> FnOnce(&'r mut Self::Tx) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+        E: From<sqlx::Error> + Send + 'a,
+        R: Send + 'a,
+    {
+        let mut tx = self.begin().await?;
+        let res = callback(&mut tx).await;
+        commit_or_rollback(tx, res).await
+    }
+}
+
+/// The number of rows affected by a statement.
+pub trait RowsAffected {
+    fn rows_affected(&self) -> u64;
+}
+
+impl RowsAffected for u64 {
+    fn rows_affected(&self) -> u64 {
+        *self
+    }
+}
+
+impl<T: RowsAffected> RowsAffected for &T {
+    fn rows_affected(&self) -> u64 {
+        (**self).rows_affected()
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl RowsAffected for sqlx::postgres::PgQueryResult {
+    fn rows_affected(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl RowsAffected for sqlx::mysql::MySqlQueryResult {
+    fn rows_affected(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl RowsAffected for sqlx::sqlite::SqliteQueryResult {
+    fn rows_affected(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+/// Return a 409 when fewer rows than expected were affected,
+/// e.g. for an optimistic-locking `UPDATE ... WHERE id = $1 AND version = $2`.
+pub fn check_affected(result: impl RowsAffected, expected: u64) -> Result<(), HttpError> {
+    if result.rows_affected() < expected {
+        return conflict("The resource was modified concurrently");
+    }
+    Ok(())
+}
+
+/// Rows affected by the statements of a `with_tx_counting` transaction.
+#[derive(Debug, Default)]
+pub struct TxStats {
+    rows_affected: u64,
+}
+
+impl TxStats {
+    /// Add the rows affected by a statement, returning that statement count.
+    pub fn record(&mut self, result: impl RowsAffected) -> u64 {
+        let rows_affected = result.rows_affected();
+        self.rows_affected += rows_affected;
+        rows_affected
+    }
+
+    pub fn rows_affected(&self) -> u64 {
+        self.rows_affected
+    }
+}
+
+/// Run a callback with a transaction and a `TxStats` to record the rows affected by its statements.
+/// Returns the callback result along with the total rows affected once committed.
+/// If the callback returns an error, the transaction is rolled back.
+pub async fn with_tx_counting<'a, F, R, E, DB>(pool: &Pool<DB>, callback: F) -> Result<(R, u64), E>
+where
+    F: for<'r> FnOnce(
+            &'r mut Transaction<DB>,
+            &'r mut TxStats,
+        ) -> ScopedBoxFuture<'a, 'r, Result<R, E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    let mut tx = pool.begin().await?;
+    let mut stats = TxStats::default();
+    let res = callback(&mut tx, &mut stats).await;
+    let response = commit_or_rollback(tx, res).await?;
+    Ok((response, stats.rows_affected()))
+}
+
+/// Run a callback with a transaction on each of two pools, for dual writes.
+/// If the callback returns an error, both transactions are rolled back.
+/// If the callback returns Ok, `a` is committed first and `b` only if `a` committed.
+/// This is not a two-phase commit: if committing `b` fails after `a` committed,
+/// the writes to `a` can not be undone, the failure is logged as needing manual reconciliation
+/// and the commit error is returned.
+pub async fn with_two_tx<'a, F, R, E, DBA, DBB>(
+    pool_a: &Pool<DBA>,
+    pool_b: &Pool<DBB>,
+    callback: F,
+) -> Result<R, E>
+where
+    F: for<'r> FnOnce(
+            &'r mut Transaction<DBA>,
+            &'r mut Transaction<DBB>,
+        ) -> ScopedBoxFuture<'a, 'r, Result<R, E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    R: Send + 'a,
+    DBA: Database,
+    DBB: Database,
+{
+    let mut tx_a = pool_a.begin().await?;
+    let mut tx_b = pool_b.begin().await?;
+    let res = callback(&mut tx_a, &mut tx_b).await;
+
+    let response = match res {
+        Ok(response) => response,
+        Err(e) => {
+            rollback(tx_a).await?;
+            rollback(tx_b).await?;
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = tx_a.commit().await {
+        rollback(tx_b).await?;
+        return Err(e.into());
+    }
+    if let Err(e) = tx_b.commit().await {
+        tracing::error!(
+            "First transaction committed but the second failed to commit, needs manual reconciliation: {}",
+            e
+        );
+        return Err(e.into());
+    }
+    Ok(response)
+}
+
+/// A transaction that is rolled back when dropped unless `commit` was called,
+/// the imperative alternative to `with_tx` for handlers with early returns:
+/// `let mut guard = TxGuard::begin(&pool).await?; ... guard.commit().await?;`.
+/// Derefs to the wrapped `Transaction`, so queries run with `&mut **guard`.
+/// In debug builds, dropping the guard without calling `commit` or `rollback` logs a warning
+/// with the backtrace of where it was begun, to catch transactions left open by mistake.
+pub struct TxGuard<'a, DB: Database> {
+    tx: Option<Transaction<'a, DB>>,
+    #[cfg(debug_assertions)]
+    begun_at: std::backtrace::Backtrace,
+}
+
+impl<DB: Database> TxGuard<'static, DB> {
+    pub async fn begin(pool: &Pool<DB>) -> Result<TxGuard<'static, DB>, sqlx::Error> {
+        Ok(TxGuard {
+            tx: Some(pool.begin().await?),
+            #[cfg(debug_assertions)]
+            begun_at: std::backtrace::Backtrace::force_capture(),
+        })
+    }
+}
+
+impl<'a, DB: Database> TxGuard<'a, DB> {
+    pub async fn commit(mut self) -> Result<(), sqlx::Error> {
+        match self.tx.take() {
+            Some(tx) => tx.commit().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Roll back right away instead of waiting for the guard to be dropped.
+    pub async fn rollback(mut self) -> Result<(), sqlx::Error> {
+        match self.tx.take() {
+            Some(tx) => tx.rollback().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, DB: Database> Deref for TxGuard<'a, DB> {
+    type Target = Transaction<'a, DB>;
+
+    fn deref(&self) -> &Self::Target {
+        self.tx
+            .as_ref()
+            .expect("TxGuard used after commit or rollback")
+    }
+}
+
+impl<'a, DB: Database> DerefMut for TxGuard<'a, DB> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.tx
+            .as_mut()
+            .expect("TxGuard used after commit or rollback")
+    }
+}
+
+impl<'a, DB: Database> Drop for TxGuard<'a, DB> {
+    fn drop(&mut self) {
+        // Dropping a sqlx transaction queues its rollback on the connection,
+        // which runs before the connection is reused.
+        if self.tx.take().is_some() {
+            #[cfg(debug_assertions)]
+            tracing::warn!(
+                "TxGuard dropped without commit or rollback, rolling back; begun at:\n{}",
+                self.begun_at
+            );
+            #[cfg(not(debug_assertions))]
+            tracing::debug!("TxGuard dropped without commit, rolling back");
+        }
+    }
+}
+
+/// Same as `with_tx`, but the transaction only begins when the callback first calls `LazyTx::get`,
+/// for handlers that often do not touch the database, e.g. when serving from a cache.
+/// If the transaction was never begun, nothing is committed or rolled back.
+pub async fn with_tx_lazy<'a, F, R, E, DB>(pool: &Pool<DB>, callback: F) -> Result<R, E>
+where
+    F: for<'r> FnOnce(&'r mut LazyTx<DB>) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    let mut lazy = LazyTx {
+        pool: pool.clone(),
+        tx: None,
+    };
+    let res = callback(&mut lazy).await;
+    match lazy.tx {
+        Some(tx) => commit_or_rollback(tx, res).await,
+        None => res,
+    }
+}
+
+/// A transaction begun on first use, see `with_tx_lazy`.
+pub struct LazyTx<DB: Database> {
+    pool: Pool<DB>,
+    tx: Option<Transaction<'static, DB>>,
+}
+
+impl<DB: Database> LazyTx<DB> {
+    /// The transaction, begun by the first call.
+    pub async fn get(&mut self) -> Result<&mut Transaction<'static, DB>, sqlx::Error> {
+        if self.tx.is_none() {
+            self.tx = Some(self.pool.begin().await?);
+        }
+        Ok(self.tx.as_mut().expect("transaction just begun"))
+    }
+
+    /// Whether the transaction was begun.
+    pub fn is_begun(&self) -> bool {
+        self.tx.is_some()
+    }
+}
+
+/// Same as `with_tx`, but the callback also receives the clock to read the current time from,
+/// instead of the system clock, so transaction-scoped logic can be tested at a fixed time.
+/// Pass `&SystemClock` for real time and a `tests::TestClock` in tests.
+pub async fn with_tx_clock<'a, F, R, E, DB>(
+    pool: &Pool<DB>,
+    clock: &dyn Clock,
+    callback: F,
+) -> Result<R, E>
+where
+    F: for<'r> FnOnce(
+            &'r mut Transaction<DB>,
+            &'r dyn Clock,
+        ) -> ScopedBoxFuture<'a, 'r, Result<R, E>>
+        + Send
+        + 'a,
+    E: From<sqlx::Error> + Send + 'a,
+    R: Send + 'a,
+    DB: Database,
+{
+    let mut tx = pool.begin().await?;
+    let res = callback(&mut tx, clock).await;
+    commit_or_rollback(tx, res).await
+}
+
+/// A source of the current time, see `with_tx_clock`.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+static PROPAGATE_ROLLBACK_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Whether a failing rollback after a callback error is returned instead of the callback error.
+/// This also applies to `TxMiddleware` rollbacks after a handler error, and to streams
+/// rolled back after an item error.
+/// Disabled by default: the callback error is returned, as the most useful one for the client,
+/// and the rollback error is logged.
+pub fn set_propagate_rollback_errors(enabled: bool) {
+    PROPAGATE_ROLLBACK_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) async fn rollback<DB: Database>(tx: Transaction<'_, DB>) -> Result<(), sqlx::Error> {
+    match tx.rollback().await {
+        Err(e) if PROPAGATE_ROLLBACK_ERRORS.load(Ordering::Relaxed) => Err(e),
+        Err(e) => {
+            tracing::error!("Failed to rollback transaction: {}", e);
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+async fn commit_or_rollback<R, E, DB>(tx: Transaction<'_, DB>, res: Result<R, E>) -> Result<R, E>
+where
+    E: From<sqlx::Error>,
+    DB: Database,
+{
     match res {
         Ok(response) => {
             tx.commit().await?;
             Ok(response)
         }
         Err(e) => {
-            tx.rollback().await?;
+            rollback(tx).await?;
             Err(e)
         }
     }
@@ -27,8 +1020,11 @@ where
 
 /// Tests module for the tx module
 pub mod tests {
+    use super::Clock;
     use scoped_futures::ScopedBoxFuture;
     use sqlx::{Database, Pool, Transaction};
+    use std::sync::Mutex;
+    use std::time::{Duration, SystemTime};
 
     /// Run a callback with a transaction. The transaction is rolled back at the end.
     pub async fn with_tx<'a, F, DB>(pool: &Pool<DB>, f: F)
@@ -40,4 +1036,108 @@ pub mod tests {
         f(&mut tx).await;
         tx.rollback().await.expect("Failed to rollback transaction");
     }
+
+    /// A clock for tests, standing still at the given time until it is set or advanced.
+    #[derive(Debug)]
+    pub struct TestClock {
+        now: Mutex<SystemTime>,
+    }
+
+    impl TestClock {
+        pub fn new(now: SystemTime) -> TestClock {
+            TestClock {
+                now: Mutex::new(now),
+            }
+        }
+
+        pub fn set(&self, now: SystemTime) {
+            *self.now.lock().expect("TestClock lock poisoned") = now;
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            *self.now.lock().expect("TestClock lock poisoned") += duration;
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> SystemTime {
+            *self.now.lock().expect("TestClock lock poisoned")
+        }
+    }
+}
+
+#[cfg(all(test, feature = "postgres"))]
+mod tx_options_tests {
+    use super::{is_identifier, TxOptions};
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use std::time::Duration;
+
+    fn search_path(search_path: &str) -> TxOptions {
+        TxOptions {
+            search_path: Some(search_path.to_string()),
+            ..TxOptions::default()
+        }
+    }
+
+    fn role(role: &str) -> TxOptions {
+        TxOptions {
+            role: Some(role.to_string()),
+            ..TxOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_is_identifier() {
+        assert!(is_identifier("tenant_1"));
+        assert!(is_identifier("_private$"));
+        assert!(!is_identifier(""));
+        assert!(!is_identifier("1tenant"));
+        assert!(!is_identifier("a;DROP"));
+        assert!(!is_identifier("a b"));
+        assert!(!is_identifier("\"public\""));
+        assert!(!is_identifier("'public'"));
+    }
+
+    #[test]
+    fn test_statements_reject_invalid_search_path() {
+        for value in ["a;DROP", "\"public\"", "'public'", "", "a, b;", "a,", "a b"] {
+            let error = search_path(value)
+                .statements()
+                .expect_err(&format!("Expected {:?} to be rejected", value));
+            assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_statements_reject_invalid_role() {
+        for value in ["a;DROP", "\"admin\"", "'admin'", "", "a, b;"] {
+            let error = role(value)
+                .statements()
+                .expect_err(&format!("Expected {:?} to be rejected", value));
+            assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_statements() {
+        let options = TxOptions {
+            search_path: Some("tenant_1,  public".to_string()),
+            role: Some("app_user".to_string()),
+            statement_timeout: Some(Duration::from_millis(1500)),
+        };
+
+        assert_eq!(
+            options.statements().expect("Expected valid options"),
+            vec![
+                "SET LOCAL search_path TO tenant_1, public",
+                "SET LOCAL ROLE app_user",
+                "SET LOCAL statement_timeout = 1500",
+            ]
+        );
+        assert!(TxOptions::default()
+            .statements()
+            .expect("Expected valid options")
+            .is_empty());
+    }
 }
@@ -0,0 +1,42 @@
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use actix_web::{HttpRequest, Responder};
+use actix_web_sqlx_tx::audit::{set_audit_sink, AuditSink, Audited};
+use actix_web_sqlx_tx::http::{set_default_headers, set_response_hook, HttpResponse};
+use std::sync::Mutex;
+
+static RECORDED: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
+
+struct RecordingSink;
+
+impl AuditSink for RecordingSink {
+    fn record(&self, _req: &HttpRequest, response: &HttpResponse) {
+        *RECORDED.lock().unwrap() = response.headers.clone();
+    }
+}
+
+// The sink, hook and default headers are global, so this binary holds a single test.
+#[actix_rt::test]
+async fn test_audit_sink_records_the_final_response() {
+    set_audit_sink(RecordingSink);
+    set_response_hook(|response| {
+        response
+            .headers
+            .push(("X-Build".to_string(), "abc".to_string()))
+    });
+    set_default_headers(vec![("X-API-Version".to_string(), "1".to_string())]);
+
+    let req = TestRequest::get().to_http_request();
+    let res = Audited::from(HttpResponse::Ok().finish()).respond_to(&req);
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("X-Build").unwrap(), "abc");
+    assert_eq!(res.headers().get("X-API-Version").unwrap(), "1");
+    assert_eq!(
+        *RECORDED.lock().unwrap(),
+        vec![
+            ("X-Build".to_string(), "abc".to_string()),
+            ("X-API-Version".to_string(), "1".to_string()),
+        ]
+    );
+}
@@ -0,0 +1,32 @@
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use actix_web::{Responder, ResponseError};
+use actix_web_sqlx_tx::http::{set_default_headers, HttpError, HttpResponse};
+
+// Default headers are global, so this binary holds a single test.
+#[actix_rt::test]
+async fn test_default_headers_apply_to_every_response() {
+    let req = TestRequest::get().to_http_request();
+    set_default_headers(vec![("X-API-Version".to_string(), "1".to_string())]);
+
+    let items = futures::stream::iter(vec![Ok::<_, HttpError>(1)]);
+    let res = HttpResponse::stream_json_array(items)
+        .await
+        .expect("Failed to build response")
+        .respond_to(&req);
+    assert_eq!(res.headers().get("X-API-Version").unwrap(), "1");
+
+    let res = HttpError::with_status(StatusCode::NOT_FOUND, "Not found").error_response();
+    assert_eq!(res.headers().get("X-API-Version").unwrap(), "1");
+
+    let res = HttpResponse::Ok()
+        .add_header("X-API-Version", "2")
+        .finish()
+        .respond_to(&req);
+    let versions: Vec<_> = res.headers().get_all("X-API-Version").collect();
+    assert_eq!(versions, vec!["2"]);
+
+    set_default_headers(vec![("X-API-Version".to_string(), "3".to_string())]);
+    let res = HttpResponse::Ok().finish().respond_to(&req);
+    assert_eq!(res.headers().get("X-API-Version").unwrap(), "3");
+}
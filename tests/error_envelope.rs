@@ -0,0 +1,85 @@
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use actix_web::{Responder, ResponseError};
+use actix_web_sqlx_tx::http::{set_error_envelope_key, HttpError, HttpResponse};
+use serde_json::{json, Value};
+
+async fn body(error: &HttpError) -> (StatusCode, Value) {
+    let res = error.error_response();
+    let status = res.status();
+    let body = to_bytes(res.into_body())
+        .await
+        .expect("Failed to read body");
+    (
+        status,
+        serde_json::from_slice(&body).expect("Failed to parse body"),
+    )
+}
+
+// The envelope key can only be set once, so this binary holds a single test.
+#[actix_rt::test]
+async fn test_error_envelope() {
+    set_error_envelope_key("error");
+
+    let error = HttpError::conflict_with_body("Already exists", json!({ "id": 1 }));
+    assert_eq!(
+        body(&error).await,
+        (
+            StatusCode::CONFLICT,
+            json!({ "error": { "message": "Already exists", "existing": { "id": 1 } } })
+        )
+    );
+
+    let error = HttpError::passthrough(StatusCode::BAD_GATEWAY, json!({ "upstream": "down" }));
+    assert_eq!(
+        body(&error).await,
+        (StatusCode::BAD_GATEWAY, json!({ "upstream": "down" }))
+    );
+
+    let error = HttpError::multiple(vec![
+        HttpError::with_status(StatusCode::CONFLICT, "Duplicate"),
+        HttpError::passthrough(StatusCode::NOT_FOUND, json!({ "upstream": "missing" })),
+    ]);
+    assert_eq!(
+        body(&error).await,
+        (
+            StatusCode::BAD_REQUEST,
+            json!({ "error": [
+                { "status": 409, "body": { "message": "Duplicate" } },
+                { "status": 404, "body": { "upstream": "missing" } },
+            ] })
+        )
+    );
+
+    let req = TestRequest::get().to_http_request();
+    let items = || {
+        futures::stream::iter(vec![
+            Ok(1),
+            Err(HttpError::with_status(
+                StatusCode::BAD_GATEWAY,
+                "Upstream failed",
+            )),
+        ])
+    };
+    let res = HttpResponse::stream_json_array(items())
+        .await
+        .expect("Failed to build response")
+        .respond_to(&req);
+    let body = to_bytes(res.into_body())
+        .await
+        .expect("Failed to read body");
+    assert_eq!(
+        serde_json::from_slice::<Value>(&body).expect("Failed to parse body"),
+        json!([1, { "error": { "message": "Upstream failed" } }])
+    );
+
+    let res = HttpResponse::try_ndjson(items())
+        .await
+        .expect("Failed to build response")
+        .respond_to(&req);
+    let body = to_bytes(res.into_body())
+        .await
+        .expect("Failed to read body");
+    assert_eq!(body, "1\n{\"error\":{\"message\":\"Upstream failed\"}}\n");
+}
@@ -0,0 +1,170 @@
+use actix_web::body::to_bytes;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use actix_web::{Responder, ResponseError};
+use actix_web_sqlx_tx::http::{HttpError, HttpResponse};
+use actix_web_sqlx_tx::idempotency::{
+    idempotent, IdempotencyStore, StoredResponse, IDEMPOTENCY_KEY,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Default, Clone)]
+struct MemoryStore {
+    // `None` marks a key in flight.
+    entries: Arc<Mutex<HashMap<String, Option<StoredResponse>>>>,
+    fail_put: bool,
+}
+
+impl IdempotencyStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<StoredResponse>, HttpError> {
+        Ok(self.entries.lock().unwrap().get(key).cloned().flatten())
+    }
+
+    async fn reserve(&self, key: &str) -> Result<bool, HttpError> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(key) {
+            return Ok(false);
+        }
+        entries.insert(key.to_string(), None);
+        Ok(true)
+    }
+
+    async fn put(&self, key: &str, response: StoredResponse) -> Result<(), HttpError> {
+        if self.fail_put {
+            return Err(HttpError::with_status(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Store unavailable",
+            ));
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), Some(response));
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<(), HttpError> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[actix_rt::test]
+async fn test_idempotent_replays_bytes_responses() {
+    let store = MemoryStore::default();
+    let calls = AtomicUsize::new(0);
+    let req = TestRequest::post()
+        .insert_header((IDEMPOTENCY_KEY, "payment-1"))
+        .to_http_request();
+
+    let mut bodies = vec![];
+    for _ in 0..2 {
+        let res = idempotent(&store, &req, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(HttpResponse::Ok().json_pretty(json!({ "charged": 10 })))
+        })
+        .await
+        .expect("Failed to run handler")
+        .respond_to(&req);
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "application/json");
+        bodies.push(
+            to_bytes(res.into_body())
+                .await
+                .expect("Failed to read body"),
+        );
+    }
+
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(bodies[0], bodies[1]);
+}
+
+#[actix_rt::test]
+async fn test_idempotent_rejects_a_key_in_flight() {
+    let store = MemoryStore::default();
+    assert!(store.reserve("payment-1").await.unwrap());
+    let req = TestRequest::post()
+        .insert_header((IDEMPOTENCY_KEY, "payment-1"))
+        .to_http_request();
+
+    let res = idempotent(&store, &req, || async {
+        panic!("The handler must not run while the key is in flight")
+    })
+    .await;
+
+    match res {
+        Err(e) => assert_eq!(e.status_code(), StatusCode::CONFLICT),
+        Ok(_) => panic!("Expected a conflict"),
+    }
+}
+
+#[actix_rt::test]
+async fn test_idempotent_releases_the_key_on_error() {
+    let store = MemoryStore::default();
+    let req = TestRequest::post()
+        .insert_header((IDEMPOTENCY_KEY, "payment-1"))
+        .to_http_request();
+
+    let res = idempotent(&store, &req, || async {
+        Err(HttpError::with_status(
+            StatusCode::BAD_GATEWAY,
+            "Upstream failed",
+        ))
+    })
+    .await;
+    assert!(res.is_err());
+
+    assert!(store.reserve("payment-1").await.unwrap());
+}
+
+#[actix_rt::test]
+async fn test_idempotent_keeps_the_key_in_flight_when_storing_fails() {
+    let store = MemoryStore {
+        fail_put: true,
+        ..MemoryStore::default()
+    };
+    let req = TestRequest::post()
+        .insert_header((IDEMPOTENCY_KEY, "payment-1"))
+        .to_http_request();
+
+    let res = idempotent(&store, &req, || async {
+        Ok(HttpResponse::Ok().json(json!({ "charged": 10 })))
+    })
+    .await
+    .expect("Failed to run handler")
+    .respond_to(&req);
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = idempotent(&store, &req, || async {
+        panic!("The handler must not run again after storing failed")
+    })
+    .await;
+    match res {
+        Err(e) => assert_eq!(e.status_code(), StatusCode::CONFLICT),
+        Ok(_) => panic!("Expected a conflict"),
+    }
+}
+
+#[actix_rt::test]
+async fn test_idempotent_releases_the_key_when_cancelled() {
+    let store = MemoryStore::default();
+    let req = TestRequest::post()
+        .insert_header((IDEMPOTENCY_KEY, "payment-1"))
+        .to_http_request();
+
+    let res = actix_rt::time::timeout(
+        Duration::from_millis(10),
+        idempotent(&store, &req, futures::future::pending),
+    )
+    .await;
+    assert!(res.is_err());
+    actix_rt::time::sleep(Duration::from_millis(10)).await;
+
+    assert!(store.reserve("payment-1").await.unwrap());
+}
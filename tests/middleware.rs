@@ -0,0 +1,119 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use actix_web_sqlx_tx::middleware::{Tx, TxMiddleware, TxOutcome};
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{Sqlite, SqlitePool};
+use std::time::Duration;
+
+async fn pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Failed to create pool");
+    sqlx::query("CREATE TABLE users (email TEXT NOT NULL)")
+        .execute(&pool)
+        .await
+        .expect("Failed to create table");
+    pool
+}
+
+async fn count_users(pool: &SqlitePool) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(pool)
+        .await
+        .expect("Failed to count users")
+}
+
+async fn insert_user(tx: &Tx<Sqlite>) {
+    let mut tx = tx.lock().await;
+    sqlx::query("INSERT INTO users (email) VALUES (?)")
+        .bind("someemail")
+        .execute(&mut **tx)
+        .await
+        .expect("Failed to insert user");
+}
+
+async fn create(tx: Tx<Sqlite>, status: web::Path<u16>) -> HttpResponse {
+    insert_user(&tx).await;
+    HttpResponse::new(StatusCode::from_u16(status.into_inner()).expect("Invalid status"))
+}
+
+async fn create_forced(tx: Tx<Sqlite>) -> HttpResponse {
+    insert_user(&tx).await;
+    tx.force_commit().await;
+    HttpResponse::BadRequest().finish()
+}
+
+fn keep_client_errors(status: StatusCode) -> TxOutcome {
+    if status.is_server_error() {
+        TxOutcome::Rollback
+    } else {
+        TxOutcome::Commit
+    }
+}
+
+async fn users_after(middleware: TxMiddleware<Sqlite>, pool: &SqlitePool, uri: &str) -> i64 {
+    let app = init_service(
+        App::new()
+            .wrap(middleware)
+            .route("/users/forced", web::post().to(create_forced))
+            .route("/users/{status}", web::post().to(create)),
+    )
+    .await;
+    call_service(&app, TestRequest::post().uri(uri).to_request()).await;
+    count_users(pool).await
+}
+
+#[actix_rt::test]
+async fn test_middleware_commits_on_success() {
+    let pool = pool().await;
+    let middleware = TxMiddleware::new(pool.clone());
+    assert_eq!(users_after(middleware, &pool, "/users/201").await, 1);
+}
+
+#[actix_rt::test]
+async fn test_middleware_rolls_back_client_errors_by_default() {
+    let pool = pool().await;
+    let middleware = TxMiddleware::new(pool.clone());
+    assert_eq!(users_after(middleware, &pool, "/users/409").await, 0);
+}
+
+#[actix_rt::test]
+async fn test_middleware_policy_can_keep_client_errors() {
+    let pool = pool().await;
+    let middleware = TxMiddleware::new(pool.clone()).policy(keep_client_errors);
+    assert_eq!(users_after(middleware, &pool, "/users/409").await, 1);
+
+    let middleware = TxMiddleware::new(pool.clone()).policy(keep_client_errors);
+    assert_eq!(users_after(middleware, &pool, "/users/500").await, 1);
+}
+
+#[actix_rt::test]
+async fn test_middleware_force_commit_overrides_the_policy() {
+    let pool = pool().await;
+    let middleware = TxMiddleware::new(pool.clone());
+    assert_eq!(users_after(middleware, &pool, "/users/forced").await, 1);
+}
+
+#[actix_rt::test]
+async fn test_middleware_only_begins_when_tx_is_extracted() {
+    let pool = pool().await;
+    let app = init_service(
+        App::new()
+            .wrap(TxMiddleware::new(pool.clone()))
+            .route("/health", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    // The pool has a single connection, so beginning eagerly would wait for it.
+    let _connection = pool.acquire().await.expect("Failed to acquire connection");
+    let res = actix_rt::time::timeout(
+        Duration::from_secs(1),
+        call_service(&app, TestRequest::get().uri("/health").to_request()),
+    )
+    .await
+    .expect("The request waited for a connection");
+    assert_eq!(res.status(), StatusCode::OK);
+}
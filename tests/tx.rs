@@ -0,0 +1,336 @@
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use actix_web_sqlx_tx::http::{conflict, ok, HttpError, Response};
+use actix_web_sqlx_tx::tx::tests::TestClock;
+use actix_web_sqlx_tx::tx::{
+    with_tx, with_tx_clock, with_tx_deadline, with_tx_retry_budget, RetryableError, TxGuard,
+    TxRunner,
+};
+use scoped_futures::ScopedBoxFuture;
+use scoped_futures::ScopedFutureExt;
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::SqlitePool;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+async fn pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Failed to create pool");
+    sqlx::query("CREATE TABLE users (id BLOB, email TEXT NOT NULL)")
+        .execute(&pool)
+        .await
+        .expect("Failed to create table");
+    pool
+}
+
+async fn count_users(pool: &SqlitePool) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(pool)
+        .await
+        .expect("Failed to count users")
+}
+
+#[actix_rt::test]
+async fn test_with_tx_commits_on_ok() {
+    let pool = pool().await;
+
+    let res: Response = with_tx(&pool, |tx| {
+        async move {
+            sqlx::query("INSERT INTO users (email) VALUES (?)")
+                .bind("someemail")
+                .execute(&mut **tx)
+                .await?;
+            ok("created")
+        }
+        .scope_boxed()
+    })
+    .await;
+
+    assert!(res.is_ok());
+    assert_eq!(count_users(&pool).await, 1);
+}
+
+#[actix_rt::test]
+async fn test_with_tx_rolls_back_and_returns_conflict() {
+    let pool = pool().await;
+
+    let res: Response = with_tx(&pool, |tx| {
+        async move {
+            sqlx::query("INSERT INTO users (email) VALUES (?)")
+                .bind("someemail")
+                .execute(&mut **tx)
+                .await?;
+            conflict("User already exists")
+        }
+        .scope_boxed()
+    })
+    .await;
+
+    match res {
+        Err(e) => assert_eq!(e.status_code(), StatusCode::CONFLICT),
+        Ok(_) => panic!("Expected a conflict"),
+    }
+    assert_eq!(count_users(&pool).await, 0);
+}
+
+#[actix_rt::test]
+async fn test_tx_guard_commits() {
+    let pool = pool().await;
+
+    let mut guard = TxGuard::begin(&pool).await.expect("Failed to begin");
+    sqlx::query("INSERT INTO users (email) VALUES (?)")
+        .bind("someemail")
+        .execute(&mut **guard)
+        .await
+        .expect("Failed to insert");
+    guard.commit().await.expect("Failed to commit");
+
+    assert_eq!(count_users(&pool).await, 1);
+}
+
+#[actix_rt::test]
+async fn test_tx_guard_rolls_back_on_drop() {
+    let pool = pool().await;
+
+    {
+        let mut guard = TxGuard::begin(&pool).await.expect("Failed to begin");
+        sqlx::query("INSERT INTO users (email) VALUES (?)")
+            .bind("someemail")
+            .execute(&mut **guard)
+            .await
+            .expect("Failed to insert");
+    }
+
+    assert_eq!(count_users(&pool).await, 0);
+}
+
+#[actix_rt::test]
+async fn test_with_tx_returns_inserted_id() {
+    let pool = pool().await;
+
+    let res: Result<Uuid, HttpError> = with_tx(&pool, |tx| {
+        async move {
+            let id: Uuid =
+                sqlx::query_scalar("INSERT INTO users (id, email) VALUES (?, ?) RETURNING id")
+                    .bind(Uuid::new_v4())
+                    .bind("someemail")
+                    .fetch_one(&mut **tx)
+                    .await?;
+            Ok(id)
+        }
+        .scope_boxed()
+    })
+    .await;
+
+    let id = res.expect("Failed to insert user");
+    let stored: Uuid = sqlx::query_scalar("SELECT id FROM users WHERE email = ?")
+        .bind("someemail")
+        .fetch_one(&pool)
+        .await
+        .expect("Failed to fetch user");
+    assert_eq!(stored, id);
+}
+
+#[actix_rt::test]
+async fn test_with_tx_clock_reads_the_injected_clock() {
+    let pool = pool().await;
+    let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_000));
+    clock.advance(Duration::from_secs(60));
+
+    let res: Result<SystemTime, HttpError> = with_tx_clock(&pool, &clock, |tx, clock| {
+        async move {
+            sqlx::query("INSERT INTO users (email) VALUES (?)")
+                .bind("someemail")
+                .execute(&mut **tx)
+                .await?;
+            Ok(clock.now())
+        }
+        .scope_boxed()
+    })
+    .await;
+
+    assert_eq!(
+        res.expect("Failed to insert user"),
+        UNIX_EPOCH + Duration::from_secs(1_060)
+    );
+    assert_eq!(count_users(&pool).await, 1);
+}
+
+#[derive(Default)]
+struct RecordingRunner {
+    outcomes: Mutex<Vec<&'static str>>,
+}
+
+impl TxRunner for RecordingRunner {
+    type Tx = Vec<String>;
+
+    async fn run<'a, F, R, E>(&'a self, callback: F) -> Result<R, E>
+    where
+        F: for<'r> FnOnce(&'r mut Self::Tx) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+        E: From<sqlx::Error> + Send + 'a,
+        R: Send + 'a,
+    {
+        let mut statements = vec![];
+        let res = callback(&mut statements).await;
+        let outcome = if res.is_ok() { "commit" } else { "rollback" };
+        self.outcomes.lock().unwrap().push(outcome);
+        res
+    }
+}
+
+async fn register<T: TxRunner<Tx = Vec<String>>>(runner: &T, email: &'static str) -> Response {
+    runner
+        .run(|tx| {
+            async move {
+                tx.push(format!("INSERT INTO users (email) VALUES ('{email}')"));
+                if email.contains('@') {
+                    ok("registered")
+                } else {
+                    conflict("Invalid email")
+                }
+            }
+            .scope_boxed()
+        })
+        .await
+}
+
+#[actix_rt::test]
+async fn test_tx_runner_can_be_faked() {
+    let runner = RecordingRunner::default();
+
+    assert!(register(&runner, "a@b.c").await.is_ok());
+    match register(&runner, "someemail").await {
+        Err(e) => assert_eq!(e.status_code(), StatusCode::CONFLICT),
+        Ok(_) => panic!("Expected a conflict"),
+    }
+
+    assert_eq!(*runner.outcomes.lock().unwrap(), vec!["commit", "rollback"]);
+}
+
+#[derive(Debug)]
+enum AttemptError {
+    Conflict,
+    Http(HttpError),
+}
+
+impl From<HttpError> for AttemptError {
+    fn from(e: HttpError) -> Self {
+        AttemptError::Http(e)
+    }
+}
+
+impl From<sqlx::Error> for AttemptError {
+    fn from(e: sqlx::Error) -> Self {
+        AttemptError::Http(HttpError::from(e))
+    }
+}
+
+impl RetryableError for AttemptError {
+    fn is_retryable(&self) -> bool {
+        matches!(self, AttemptError::Conflict)
+    }
+}
+
+async fn insert_user(tx: &mut sqlx::SqliteConnection) -> Result<(), AttemptError> {
+    sqlx::query("INSERT INTO users (email) VALUES (?)")
+        .bind("someemail")
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+fn assert_gateway_timeout(res: Result<(), AttemptError>) {
+    match res {
+        Err(AttemptError::Http(e)) => assert_eq!(e.status_code(), StatusCode::GATEWAY_TIMEOUT),
+        res => panic!("Expected a 504, got {:?}", res),
+    }
+}
+
+#[actix_rt::test]
+async fn test_with_tx_retry_budget_retries_retryable_errors() {
+    let pool = pool().await;
+    let attempts = AtomicUsize::new(0);
+
+    let res = with_tx_retry_budget(&pool, Duration::from_secs(5), |tx| {
+        let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+        async move {
+            insert_user(tx).await?;
+            if attempt < 2 {
+                return Err(AttemptError::Conflict);
+            }
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await;
+
+    assert!(res.is_ok());
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    assert_eq!(count_users(&pool).await, 1);
+}
+
+#[actix_rt::test]
+async fn test_with_tx_retry_budget_returns_the_last_error_within_the_budget() {
+    let pool = pool().await;
+    let attempts = AtomicUsize::new(0);
+    let started = Instant::now();
+
+    // A second attempt as long as the first one would not fit in the budget.
+    let res = with_tx_retry_budget(&pool, Duration::from_millis(100), |tx| {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        async move {
+            insert_user(tx).await?;
+            actix_rt::time::sleep(Duration::from_millis(60)).await;
+            Err::<(), _>(AttemptError::Conflict)
+        }
+        .scope_boxed()
+    })
+    .await;
+
+    assert!(matches!(res, Err(AttemptError::Conflict)));
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    assert!(started.elapsed() < Duration::from_millis(100));
+    assert_eq!(count_users(&pool).await, 0);
+}
+
+#[actix_rt::test]
+async fn test_with_tx_retry_budget_cuts_off_an_attempt_at_the_budget() {
+    let pool = pool().await;
+
+    let res = with_tx_retry_budget(&pool, Duration::from_millis(50), |tx| {
+        async move {
+            insert_user(tx).await?;
+            actix_rt::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await;
+
+    assert_gateway_timeout(res);
+    assert_eq!(count_users(&pool).await, 0);
+}
+
+#[actix_rt::test]
+async fn test_with_tx_deadline_rolls_back_at_the_deadline() {
+    let pool = pool().await;
+
+    let deadline = Instant::now() + Duration::from_millis(50);
+    let res = with_tx_deadline(&pool, deadline, |tx| {
+        async move {
+            insert_user(tx).await?;
+            actix_rt::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await;
+
+    assert_gateway_timeout(res);
+    assert_eq!(count_users(&pool).await, 0);
+}
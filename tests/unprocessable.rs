@@ -0,0 +1,52 @@
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use actix_web::FromRequest;
+use actix_web_sqlx_tx::extract::ValidatedJson;
+use actix_web_sqlx_tx::http::set_unprocessable_validation_errors;
+use serde::Deserialize;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+#[derive(Debug, Deserialize)]
+struct User {
+    email: String,
+}
+
+impl Validate for User {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        if !self.email.contains('@') {
+            errors.add("email", ValidationError::new("email"));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+async fn status_for(body: &'static str) -> StatusCode {
+    let (req, mut payload) = TestRequest::post()
+        .insert_header((CONTENT_TYPE, "application/json"))
+        .set_payload(body)
+        .to_http_parts();
+    match ValidatedJson::<User>::from_request(&req, &mut payload).await {
+        Ok(_) => StatusCode::OK,
+        Err(e) => e.as_response_error().status_code(),
+    }
+}
+
+// The 422 option is global, so this binary holds a single test.
+#[actix_rt::test]
+async fn test_unprocessable_validation_errors_keep_malformed_requests_at_400() {
+    set_unprocessable_validation_errors(true);
+
+    assert_eq!(status_for("{").await, StatusCode::BAD_REQUEST);
+    assert_eq!(status_for(r#"{"email": 1}"#).await, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        status_for(r#"{"email": "someemail"}"#).await,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+    assert_eq!(status_for(r#"{"email": "a@b.c"}"#).await, StatusCode::OK);
+}